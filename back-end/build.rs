@@ -2,4 +2,32 @@
 fn main() {
     // trigger recompilation when a new migration is added
     println!("cargo:rerun-if-changed=migrations");
+
+    // The commit the binary is built from, for the /version endpoint. Builds outside a
+    // git checkout (e.g. in Docker) can pass it in as $GIT_HASH; otherwise it is "unknown".
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let git_hash = std::env::var("GIT_HASH")
+        .ok()
+        .or_else(|| git(&["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    // Rebuild when HEAD moves, so the hash stays current:
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        // A packed ref has no file of its own; watching a missing file would rebuild every time.
+        let head_ref = git(&["symbolic-ref", "-q", "HEAD"])
+            .map(|head_ref| format!("{}/{}", git_dir, head_ref));
+        if let Some(head_ref) = head_ref.filter(|path| std::path::Path::new(path).exists()) {
+            println!("cargo:rerun-if-changed={}", head_ref);
+        }
+    }
 }
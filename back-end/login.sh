@@ -4,10 +4,20 @@ set -a
 source .env
 set +a
 
+# Create the account (fails harmlessly with 409 if it already exists):
 curl -X POST http://localhost:8000/api/v1/register \
   -H "Content-Type: application/json" \
   -d "{
     \"full_name\": \"Nathan Metens\",
-    \"email\": \"nathan@example.com\",
-    \"password\": \"$REG_PASSWORD\"
+    \"email\": \"$QUOTE_EMAIL\",
+    \"password\": \"$QUOTE_PASSWORD\"
+  }"
+
+echo
+
+curl -X POST http://localhost:8000/api/v1/login \
+  -H "Content-Type: application/json" \
+  -d "{
+    \"email\": \"$QUOTE_EMAIL\",
+    \"password\": \"$QUOTE_PASSWORD\"
   }"
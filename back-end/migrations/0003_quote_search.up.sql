@@ -0,0 +1,25 @@
+-- Full-text index over the quote text and author, kept in sync with quotes by triggers.
+create virtual table quotes_fts using fts5(
+  quote_id unindexed,
+  quote,
+  author,
+  prefix = '2 3'
+);
+
+insert into quotes_fts (quote_id, quote, author)
+  select id, quote, author from quotes;
+
+create trigger quotes_fts_insert after insert on quotes begin
+  insert into quotes_fts (quote_id, quote, author)
+    values (new.id, new.quote, new.author);
+end;
+
+create trigger quotes_fts_delete after delete on quotes begin
+  delete from quotes_fts where quote_id = old.id;
+end;
+
+create trigger quotes_fts_update after update on quotes begin
+  delete from quotes_fts where quote_id = old.id;
+  insert into quotes_fts (quote_id, quote, author)
+    values (new.id, new.quote, new.author);
+end;
@@ -0,0 +1,8 @@
+create table users (
+  id integer primary key autoincrement,
+  email varchar(200) unique not null collate nocase,
+  full_name varchar(200) not null,
+  password_hash varchar(200) not null,
+  disabled boolean not null default false,
+  created_at timestamp not null default current_timestamp
+);
@@ -0,0 +1,6 @@
+alter table users add column role varchar(20) not null default 'reader';
+
+-- Existing accounts could already write, so keep them as editors.
+update users set role = 'editor';
+
+-- No account is made an admin here: admins are added with `quote-server user add --role admin`.
@@ -0,0 +1,3 @@
+drop table if exists revoked_tokens;
+drop index if exists refresh_tokens_family;
+drop table if exists refresh_tokens;
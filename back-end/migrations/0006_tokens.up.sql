@@ -0,0 +1,18 @@
+-- Refresh tokens are stored as SHA-256 hashes. Each login starts a new family; refreshing
+-- revokes the old token and adds a new one to the same family.
+create table refresh_tokens (
+  token_hash varchar(64) primary key not null,
+  family varchar(64) not null,
+  email varchar(200) not null collate nocase,
+  expires_at integer not null,
+  revoked boolean not null default false,
+  FOREIGN KEY (email) REFERENCES users(email)
+);
+
+create index refresh_tokens_family on refresh_tokens (family);
+
+-- Access tokens revoked before they expire, by their jti claim.
+create table revoked_tokens (
+  jti varchar(64) primary key not null,
+  expires_at integer not null
+);
@@ -0,0 +1 @@
+drop index if exists tags_tag_quote_id;
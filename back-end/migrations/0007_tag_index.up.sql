@@ -0,0 +1,3 @@
+-- Tag filters look quotes up by tag, so index the tags by tag first. The quote_id makes
+-- the index covering: the lookups never need to visit the table itself.
+create index if not exists tags_tag_quote_id on tags (tag, quote_id);
@@ -0,0 +1 @@
+drop index if exists tags_quote_id_tag;
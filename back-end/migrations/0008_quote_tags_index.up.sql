@@ -0,0 +1,3 @@
+-- Quotes are read back with their tags gathered by quote id, so index the tags by quote
+-- first as well. Like tags_tag_quote_id, the index covers the lookups on its own.
+create index if not exists tags_quote_id_tag on tags (quote_id, tag);
@@ -2,41 +2,202 @@
 /// These are the endpoints in this API:
 ///     1) get_quote
 ///     2) get_tagged_quote
-///     3) get_random_quote
+///     3) get_random_quote and get_random_quotes
 ///     4) add_quote 
 ///     5) delete_quote 
-///     6) get_all_quotes
-///     7) register via jwt auth
+///     6) update_quote (PUT) and patch_quote (PATCH)
+///     7) get_all_quotes
+///     8) search
+///     9) register, login, token refresh and logout via jwt auth
+///    10) update_user (admin only)
+///    11) jwks (public keys for verifying our tokens)
+///    12) import and export of the whole quote collection
+///    13) healthz, readyz, version and metrics, served at the root for monitoring (see probe_router)
 /// It uses utoipa for OpenAPI generation of the Swagger compatible docs.
 /// There is a get_quote_by_id asychrnous method that is used in all api methods.
 /// AppState is shared between enpoints to allow asynchronous visits.
 /// All quotes are fetched using anychronous calls to the database.
-
-use crate::authjwt::make_jwt_token;
+/// Every endpoint reports failures as an ApiError, sent as application/problem+json.
+use crate::authjwt::AdminRole;
 use crate::authjwt::AuthBody;
 use crate::authjwt::AuthError;
+use crate::authjwt::Authorized;
+use crate::authjwt::EditorRole;
+use crate::authjwt::Login;
+use crate::authjwt::ReaderRole;
+use crate::authjwt::RefreshRequest;
 use crate::authjwt::Registration;
+use crate::authjwt::SecurityAddon;
+use crate::error::ApiError;
+use crate::error::FieldError;
 use crate::http::StatusCode;
 use crate::*;
 
 #[derive(OpenApi)]
 #[openapi(
+    modifiers(&SecurityAddon),
     tags(
-        (name = "quote-server", description = "Famous Quote API") // Swagger UI grouping.
+        (name = "quote-server", description = "Famous Quote API"), // Swagger UI grouping.
+        (name = "monitoring", description = "Liveness, readiness, version and metrics")
     )
 )]
 pub struct ApiDoc; // Struct to create api references.
 
 // Function that generates all the api endpoints with OpenApi:
-pub fn router() -> OpenApiRouter<Arc<RwLock<AppState>>> {
+pub fn router() -> OpenApiRouter<SharedAppState> {
     OpenApiRouter::new()
-        .routes(routes!(get_quote))
+        .routes(routes!(get_quote, update_quote, patch_quote))
         .routes(routes!(get_tagged_quote))
         .routes(routes!(get_random_quote))
+        .routes(routes!(get_random_quotes))
         .routes(routes!(add_quote))
         .routes(routes!(delete_quote))
         .routes(routes!(get_all_quotes))
+        .routes(routes!(search))
         .routes(routes!(register))
+        .routes(routes!(login))
+        .routes(routes!(refresh_token))
+        .routes(routes!(logout))
+        .routes(routes!(update_user))
+        .routes(routes!(jwks))
+        .routes(routes!(import_quotes))
+        .routes(routes!(export_quotes))
+}
+
+// The liveness, readiness, version and metrics endpoints. They are served at the root
+// rather than under /api/v1, and without request tracing or metrics, since they are
+// polled constantly:
+pub fn probe_router() -> OpenApiRouter<SharedAppState> {
+    OpenApiRouter::new()
+        .routes(routes!(healthz))
+        .routes(routes!(readyz))
+        .routes(routes!(version))
+        .routes(routes!(get_metrics))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Health {
+    #[schema(example = "ok")]
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    #[schema(example = "ready")]
+    status: &'static str,
+    #[schema(example = 6)]
+    schema_version: Option<i64>, // The newest applied migration.
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct VersionInfo {
+    #[schema(example = "0.1.0")]
+    version: &'static str, // The crate version.
+    #[schema(example = "0fbe2af")]
+    git_hash: &'static str, // The commit the binary was built from, see build.rs.
+    #[schema(example = 6)]
+    schema_version: Option<i64>, // The newest applied migration, if the database answers.
+}
+
+// The newest applied migration, and whether every migration built into the binary has
+// been applied. One small query against the migrations table:
+async fn schema_status(db: &SqlitePool) -> Result<(Option<i64>, bool), sqlx::Error> {
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(db)
+            .await?;
+    let current = sqlx::migrate!()
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .all(|m| applied.contains(&m.version));
+    Ok((applied.iter().max().copied(), current))
+}
+
+// Route created: /healthz
+// The process is up and answering requests. Nothing else is checked.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "The server is alive", body = Health)
+    )
+)]
+pub async fn healthz() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+// Route created: /readyz
+// The server can do its work: the database answers and its schema is up to date.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "Ready to serve requests", body = Readiness),
+        (status = 503, description = "Database unreachable or migrations pending", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn readyz(State(app_state): State<SharedAppState>) -> Result<Json<Readiness>, ApiError> {
+    let (schema_version, current) = schema_status(&app_state.db)
+        .await
+        .map_err(|e| ApiError::Unavailable(format!("database unreachable: {}", e)))?;
+    if !current {
+        return Err(ApiError::Unavailable(
+            "database migrations are pending".to_string(),
+        ));
+    }
+    Ok(Json(Readiness {
+        status: "ready",
+        schema_version,
+    }))
+}
+
+// Route created: /metrics
+// Request counts and latencies, query latencies, pool use, quote and tag counts and auth
+// failures, in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain; version=0.0.4"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn get_metrics(
+    State(app_state): State<SharedAppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let text = metrics::render(&app_state.db).await?;
+    Ok((
+        [(http::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        text,
+    ))
+}
+
+// Route created: /version
+// What is running: the crate version, the git commit and the database schema version.
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "Build and schema versions", body = VersionInfo)
+    )
+)]
+pub async fn version(State(app_state): State<SharedAppState>) -> Json<VersionInfo> {
+    let schema_version = match schema_status(&app_state.db).await {
+        Ok((schema_version, _)) => schema_version,
+        Err(e) => {
+            log::warn!("schema version lookup failed: {}", e);
+            None
+        }
+    };
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        schema_version,
+    })
 }
 
 #[utoipa::path(
@@ -44,33 +205,133 @@ pub fn router() -> OpenApiRouter<Arc<RwLock<AppState>>> {
     path = "/register",
     request_body = Registration,
     responses(
-        (status = 200, description = "JWT token returned", body = AuthBody),
-        (status = 401, description = "Invalid registration")
+        (status = 200, description = "Account created, JWT token returned", body = AuthBody),
+        (status = 401, description = "Invalid registration", body = AuthError, content_type = "application/problem+json"),
+        (status = 409, description = "Email is already registered", body = AuthError, content_type = "application/problem+json")
     )
 )]
 pub async fn register(
-    State(app_state): State<Arc<RwLock<AppState>>>,
+    State(app_state): State<SharedAppState>,
     Json(payload): Json<Registration>,
 ) -> Result<AuthBody, AuthError> {
-    let reader = app_state.read().await;
-    make_jwt_token(&reader, &payload)
+    authjwt::register_user(&app_state, &payload).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    request_body = Login,
+    responses(
+        (status = 200, description = "JWT token returned", body = AuthBody),
+        (status = 401, description = "Wrong credentials", body = AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Account is disabled", body = AuthError, content_type = "application/problem+json")
+    )
+)]
+pub async fn login(
+    State(app_state): State<SharedAppState>,
+    Json(payload): Json<Login>,
+) -> Result<AuthBody, AuthError> {
+    authjwt::login_user(&app_state, &payload).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/token/refresh",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "New access and refresh tokens returned", body = AuthBody),
+        (status = 401, description = "Invalid, expired or reused refresh token", body = AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Account is disabled", body = AuthError, content_type = "application/problem+json")
+    )
+)]
+pub async fn refresh_token(
+    State(app_state): State<SharedAppState>,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<AuthBody, AuthError> {
+    authjwt::refresh_user(&app_state, &payload).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    request_body(content = Option<RefreshRequest>, description = "Refresh token to revoke along with the access token"),
+    security(("jwt" = [])),
+    responses(
+        (status = 204, description = "Tokens revoked"),
+        (status = 401, description = "Auth Error", body = AuthError, content_type = "application/problem+json")
+    )
+)]
+pub async fn logout(
+    State(app_state): State<SharedAppState>,
+    claims: authjwt::Claims,
+    payload: Option<Json<RefreshRequest>>,
+) -> Result<StatusCode, AuthError> {
+    let request = payload.as_ref().map(|Json(request)| request);
+    authjwt::logout_user(&app_state, &claims, request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Route created: /jwks.json (also served at /.well-known/jwks.json)
+// The public keys of the asymmetric signing keys, so other services can verify our tokens.
+#[utoipa::path(
+    get,
+    path = "/jwks.json",
+    responses(
+        (status = 200, description = "JSON Web Key Set of the token verification keys", body = Object)
+    )
+)]
+pub async fn jwks(State(app_state): State<SharedAppState>) -> impl IntoResponse {
+    Json(app_state.jwt_keys().jwks())
+}
+
+// Changes an admin can make to a user account. Missing fields are left unchanged:
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UserPatch {
+    role: Option<Role>,
+    disabled: Option<bool>,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/{email}",
+    request_body = UserPatch,
+    security(("jwt" = ["admin"])),
+    responses(
+        (status = 200, description = "User updated", body = UserInfo),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Requires the admin role", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 404, description = "No such user", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn update_user(
+    State(app_state): State<SharedAppState>,
+    auth: Authorized<AdminRole>,
+    Path(email): Path<String>,
+    Json(patch): Json<UserPatch>,
+) -> Result<impl IntoResponse, ApiError> {
+    let db = &app_state.db;
+
+    let user = user::update(db, &email, patch.role, patch.disabled)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("no user with email {}", email)))?;
+
+    log::info!("user {} updated by {}", email, auth.claims.subject());
+    Ok(Json(UserInfo::from(user)))
 }
 
 // Method that queries the database looking for the quote_id that is passed in as an argument.
-async fn get_quote_by_id(
-    db: &SqlitePool,
-    quote_id: &str,
-) -> Result<response::Response, http::StatusCode> {
+async fn get_quote_by_id(db: &SqlitePool, quote_id: &str) -> Result<response::Response, ApiError> {
     let quote_result = quote::get(db, quote_id).await; // The resulting quote to return.
 
     match quote_result {
         Ok((quote, tags)) => Ok(JsonQuote::new(quote, tags).into_response()), // Wrap the quote and tags in JsonQuote struct.
 
-        Err(e) => {
-            // Quote was not found by the id provided. 404 not found displayed.
-            log::warn!("quote fetch failed: {}", e);
-            Err(http::StatusCode::NOT_FOUND)
+        // Quote was not found by the id provided. 404 not found displayed.
+        Err(sqlx::Error::RowNotFound) => {
+            Err(ApiError::NotFound(format!("no quote with id {}", quote_id)))
         }
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -81,65 +342,126 @@ async fn get_quote_by_id(
     path = "/quote/{quote_id}",
     responses(
         (status = 200, description = "Get a quote by id", body = [JsonQuote]),
-        (status = 404, description = "No matching quote"),
+        (status = 404, description = "No matching quote", body = ApiError, content_type = "application/problem+json"),
     )
 )]
 pub async fn get_quote(
-    State(app_state): State<Arc<RwLock<AppState>>>, // Grab the app_state.
+    State(app_state): State<SharedAppState>, // Grab the app_state.
     Path(quote_id): Path<String>,                   // Grab the quote id from the url.
-) -> Result<response::Response, http::StatusCode> {
-    let app_reader = app_state.read().await; // Extract the app state.
-
-    let db = &app_reader.db; // Grab the common database that is shared amoungst resources.
+) -> Result<response::Response, ApiError> {
+    let db = &app_state.db; // Grab the common database that is shared amoungst resources.
 
     get_quote_by_id(db, &quote_id).await // Call method and pass database and id to extract quote from database.
 }
-use axum::extract::Query;
-use std::collections::HashMap;
+// Query parameters for /tagged-quote:
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TaggedParams {
+    /// Comma-separated tags to look for.
+    tags: Option<String>,
+    /// any (the default): the quote carries at least one of the tags; all: it carries every one.
+    #[param(inline)]
+    mode: Option<TagMatch>,
+    /// Comma-separated tags the quote must not carry.
+    exclude: Option<String>,
+}
 
 #[utoipa::path(
     get,
     path = "/tagged-quote",
-    params(
-        ("tags" = Option<String>, Query, description = "Comma-separated tags")
-    ),
+    params(TaggedParams),
     responses(
         (status = 200, description = "Get a quote by tags", body = [JsonQuote]),
-        (status = 404, description = "No matching quotes"),
+        (status = 400, description = "No tags given", body = ApiError, content_type = "application/problem+json"),
+        (status = 404, description = "No matching quotes", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json"),
     )
 )]
 pub async fn get_tagged_quote(
-    State(app_state): State<Arc<RwLock<AppState>>>,
-    Query(tags_param): Query<HashMap<String, String>>, // Use HashMap to extract 'tags'
-) -> Result<response::Response, http::StatusCode> {
-    let tags_string = tags_param.get("tags").cloned().unwrap_or_default();
-
-    let tags: Vec<String> = tags_string
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .collect();
+    State(app_state): State<SharedAppState>,
+    Query(params): Query<TaggedParams>,
+) -> Result<response::Response, ApiError> {
+    let filter = TagFilter::new(
+        params.tags.as_deref(),
+        params.mode,
+        params.exclude.as_deref(),
+    );
+    if filter.is_empty() {
+        return Err(ApiError::BadRequest(
+            "give tags or exclude to pick a quote by".to_string(),
+        ));
+    }
 
-    log::info!("get tagged quote: {:?}", tags);
+    log::info!("get tagged quote: {:?}", filter);
 
-    let app_reader = app_state.read().await;
-    let db = &app_reader.db;
+    let db = &app_state.db;
 
-    let quote_result = quote::get_tagged(db, tags.iter().map(String::as_ref)).await;
+    let quote_result = quote::get_tagged(db, &filter).await?;
 
     match quote_result {
-        Ok(Some(quote_id)) => get_quote_by_id(db, &quote_id).await,
-        Ok(None) => {
-            log::warn!("quote tag fetch failed tagging");
-            Err(http::StatusCode::NOT_FOUND)
-        }
-        Err(e) => {
-            log::warn!("quote tag fetch failed: {}", e);
-            Err(http::StatusCode::NOT_FOUND)
-        }
+        Some(quote_id) => get_quote_by_id(db, &quote_id).await,
+        None => Err(ApiError::NotFound(format!("no quote is {}", filter))),
     }
 }
 
+// Default and maximum number of results returned by /search:
+const DEFAULT_SEARCH_LIMIT: u32 = 10;
+const MAX_SEARCH_LIMIT: u32 = 100;
+
+// Query parameters for /search:
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Words to search for. Supports "quoted phrases", prefix* terms and author:name.
+    q: String,
+    /// Maximum number of results to return (default 10, at most 100).
+    limit: Option<u32>,
+    /// Only return quotes carrying these comma-separated tags, matched according to mode.
+    tags: Option<String>,
+    /// any (the default) or all of tags.
+    #[param(inline)]
+    mode: Option<TagMatch>,
+    /// Leave out quotes carrying any of these comma-separated tags.
+    exclude: Option<String>,
+}
+
+// Route created: /search?q=...
+// Full-text search over quote text and authors, best matches first.
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(SearchParams),
+    responses(
+        (status = 200, description = "Ranked matching quotes with highlighted snippets", body = [SearchHit]),
+        (status = 400, description = "Empty search", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn search(
+    State(app_state): State<SharedAppState>,
+    Query(params): Query<SearchParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let expression = quote::search_expression(&params.q)
+        .ok_or_else(|| ApiError::BadRequest("search has no words to look for".to_string()))?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let tags = TagFilter::new(
+        params.tags.as_deref(),
+        params.mode,
+        params.exclude.as_deref(),
+    );
+
+    log::info!("search quotes: {}", expression);
+
+    let db = &app_state.db;
+
+    let hits = quote::search(db, &expression, &tags, limit).await?;
+
+    Ok(axum::Json(hits))
+}
+
 // Route created: /random-quote
 // Grabs a random quote from the database.
 #[utoipa::path(
@@ -147,199 +469,658 @@ pub async fn get_tagged_quote(
     path = "/random-quote",
     responses(
         (status = 200, description = "Get a random quote", body = [JsonQuote]),
-        (status = 404, description = "No quote"),
+        (status = 404, description = "No quote", body = ApiError, content_type = "application/problem+json"),
     )
 )]
 pub async fn get_random_quote(
-    State(app_state): State<Arc<RwLock<AppState>>>, // Extract the shared app state.
-) -> Result<response::Response, http::StatusCode> {
-    let app_reader = app_state.read().await;
-
-    let db = &app_reader.db; // Grab the database.
+    State(app_state): State<SharedAppState>, // Extract the shared app state.
+) -> Result<response::Response, ApiError> {
+    let db = &app_state.db; // Grab the database.
 
-    let quote_result = quote::get_random(db).await; // Random quote selected from db.
+    let quote_result = quote::get_random(db, &mut fastrand::Rng::new()).await; // Random quote selected from db.
 
     match quote_result {
         Ok(quote_id) => get_quote_by_id(db, &quote_id).await, // Found the quote.
 
-        Err(e) => {
-            log::warn!("get random quote failed: {}", e); // Error
-            Err(http::StatusCode::NOT_FOUND)
-        }
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::NotFound("there are no quotes".to_string())),
+        Err(e) => Err(e.into()),
     }
 }
 
+// Default and maximum number of quotes returned by /random-quotes:
+const DEFAULT_RANDOM_COUNT: u32 = 1;
+const MAX_RANDOM_COUNT: u32 = 100;
+
+// Query parameters for /random-quotes:
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RandomQuotesParams {
+    /// Number of distinct quotes to return (default 1, at most 100).
+    count: Option<u32>,
+    /// Only pick quotes carrying these comma-separated tags, matched according to mode.
+    tags: Option<String>,
+    /// any (the default) or all of tags.
+    #[param(inline)]
+    mode: Option<TagMatch>,
+    /// Leave out quotes carrying any of these comma-separated tags.
+    exclude: Option<String>,
+    /// Any text, such as a user and a date. The same seed picks the same quotes, in the same
+    /// order, for as long as no matching quote is added or deleted.
+    seed: Option<String>,
+}
+
+// The random number seed for a /random-quotes seed. Seeds are hashed rather than parsed
+// so that any text will do, with SHA-256 so they pick the same quotes on every build and
+// platform:
+fn seed_number(seed: &str) -> u64 {
+    use sha2::{Digest, Sha256};
+
+    let digest = Sha256::digest(seed.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+// Route created: /random-quotes
+// Picks several distinct random quotes at once, optionally by tags. Fewer quotes come back
+// if fewer match, and none if none do.
+#[utoipa::path(
+    get,
+    path = "/random-quotes",
+    params(RandomQuotesParams),
+    responses(
+        (status = 200, description = "Get distinct random quotes", body = [JsonQuote]),
+        (status = 400, description = "Bad request", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json"),
+    )
+)]
+pub async fn get_random_quotes(
+    State(app_state): State<SharedAppState>,
+    Query(params): Query<RandomQuotesParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let count = params.count.unwrap_or(DEFAULT_RANDOM_COUNT);
+    if !(1..=MAX_RANDOM_COUNT).contains(&count) {
+        return Err(ApiError::BadRequest(format!(
+            "count must be between 1 and {}",
+            MAX_RANDOM_COUNT
+        )));
+    }
+    let filter = TagFilter::new(
+        params.tags.as_deref(),
+        params.mode,
+        params.exclude.as_deref(),
+    );
+    let seed = params.seed.as_deref().map(seed_number);
+
+    let quotes = quote::get_random_quotes(&app_state.db, &filter, count as usize, seed).await?;
+
+    Ok(axum::Json(quotes))
+}
+
 #[utoipa::path(
     post,
     path = "/add-quote",
     request_body = JsonQuote,
+    security(("jwt" = ["editor"])),
     responses(
-        (status = 200, description = "Quote added successfully"),
-        (status = 400, description = "Invalid input or duplicate ID"),
-        (status = 401, description = "Auth Error", body = authjwt::AuthError),
-        (status = 500, description = "Server/database error")
+        (status = 201, description = "Quote added successfully", body = JsonQuote,
+            headers(("Location" = String, description = "URL of the new quote"))),
+        (status = 400, description = "Invalid input", body = ApiError, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid quote fields", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Requires the editor role", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 409, description = "A quote with that id already exists", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Server/database error", body = ApiError, content_type = "application/problem+json")
     )
 )]
 pub async fn add_quote(
-    State(app_state): State<Arc<RwLock<AppState>>>,
-    _claims: authjwt::Claims,
-    axum::Json(json_quote): axum::Json<JsonQuote>,
-) -> Result<impl axum::response::IntoResponse, StatusCode> {
-    println!("Quote added: {:?}", json_quote);
-
-    let app_reader = app_state.read().await;
-    let db = &app_reader.db;
-
-    let (quote, tags): (Quote, Vec<&str>) = {
-        let (q, t_iter) = json_quote.to_quote();
-        (q, t_iter.collect())
-    };
+    State(app_state): State<SharedAppState>,
+    auth: Authorized<EditorRole>,
+    axum::Json(mut json_quote): axum::Json<JsonQuote>,
+) -> Result<impl axum::response::IntoResponse, ApiError> {
+    json_quote.validate().map_err(ApiError::Validation)?;
+    json_quote.assign_id(); // Clients may leave the id out and let the server pick one.
 
-    let mut tx = db.begin().await.map_err(|e| {
-        log::error!("Failed to start transaction: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Insert into quotes table
-    sqlx::query!(
-        "INSERT INTO quotes (id, quote, author) VALUES (?, ?, ?);",
-        quote.id,
-        quote.quote,
-        quote.author
-    )
-    .execute(&mut *tx)
-    .await
-    .map_err(|e| {
-        log::warn!("Insert quote failed: {}", e);
-        StatusCode::BAD_REQUEST
-    })?;
-
-    // Insert associated tags
-    for tag in tags {
-        sqlx::query!(
-            "INSERT INTO tags (quote_id, tag) VALUES (?, ?);",
-            quote.id,
-            tag
-        )
-        .execute(&mut *tx)
+    let db = &app_state.db;
+
+    let mut tx = db.begin().await?;
+
+    // Insert into the quotes and tags tables. Anything but a taken id is a server error.
+    quote::insert(&mut tx, &json_quote)
         .await
-        .map_err(|e| {
-            log::error!("Insert tag failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref dbe) if dbe.is_unique_violation() => {
+                ApiError::Conflict(format!("a quote with id {} already exists", json_quote.id))
+            }
+            e => ApiError::from(e),
         })?;
-    }
 
-    tx.commit().await.map_err(|e| {
-        log::error!("Transaction commit failed: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    tx.commit().await?;
+    log::info!("quote {} added by {}", json_quote.id, auth.claims.subject());
 
-    //Ok(StatusCode::OK)
-    Ok(axum::Json(json_quote)) // Return the quote back.
+    // Return the quote back, with its id, and point at where it can be fetched.
+    let location = format!("/api/v1/quote/{}", json_quote.id);
+    Ok((
+        StatusCode::CREATED,
+        [(http::header::LOCATION, location)],
+        axum::Json(json_quote),
+    ))
 }
 
 #[utoipa::path(
     delete,
     path = "/delete-quote/{quote_id}",
+    security(("jwt" = ["admin"])),
     responses(
         (status = 200, description = "Quote deleted successfully"),
-        (status = 401, description = "Auth Error", body = authjwt::AuthError),
-        (status = 404, description = "Quote not found"),
-        (status = 500, description = "Database error")
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Requires the admin role", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 404, description = "Quote not found", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
     )
 )]
 pub async fn delete_quote(
-    State(app_state): State<Arc<RwLock<AppState>>>,
-    _claims: authjwt::Claims,
+    State(app_state): State<SharedAppState>,
+    auth: Authorized<AdminRole>,
     Path(quote_id): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let app_reader = app_state.read().await;
-    let db = &app_reader.db;
+) -> Result<impl IntoResponse, ApiError> {
+    let db = &app_state.db;
 
     // Start transaction
-    let mut tx = db.begin().await.map_err(|e| {
-        log::error!("Failed to start transaction: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let mut tx = db.begin().await?;
 
     // First delete tags associated with the quote
     sqlx::query!("DELETE FROM tags WHERE quote_id = ?;", quote_id)
         .execute(&mut *tx)
-        .await
-        .map_err(|e| {
-            log::error!("Failed to delete tags: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .await?;
 
     // Then delete the quote
     let result = sqlx::query!("DELETE FROM quotes WHERE id = ?;", quote_id)
         .execute(&mut *tx)
-        .await
-        .map_err(|e| {
-            log::error!("Failed to delete quote: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .await?;
 
     if result.rows_affected() == 0 {
-        return Err(StatusCode::NOT_FOUND);
+        return Err(ApiError::NotFound(format!("no quote with id {}", quote_id)));
     }
 
-    tx.commit().await.map_err(|e| {
-        log::error!("Transaction commit failed: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    tx.commit().await?;
 
+    log::info!("quote {} deleted by {}", quote_id, auth.claims.subject());
     Ok((StatusCode::OK, format!("Quote {} deleted", quote_id)))
 }
 
+#[utoipa::path(
+    put,
+    path = "/quote/{quote_id}",
+    request_body = JsonQuote,
+    security(("jwt" = ["editor"])),
+    responses(
+        (status = 200, description = "Quote replaced successfully", body = JsonQuote),
+        (status = 422, description = "Invalid quote fields, or id in body does not match path", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Requires the editor role", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 404, description = "Quote not found", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn update_quote(
+    State(app_state): State<SharedAppState>,
+    auth: Authorized<EditorRole>,
+    Path(quote_id): Path<String>,
+    axum::Json(mut json_quote): axum::Json<JsonQuote>,
+) -> Result<impl IntoResponse, ApiError> {
+    json_quote.validate().map_err(ApiError::Validation)?;
+    if json_quote.id.is_empty() {
+        json_quote.id = quote_id.clone();
+    }
+    if json_quote.id != quote_id {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "id".to_string(),
+            message: format!("does not match the quote id {} in the path", quote_id),
+        }]));
+    }
+
+    let db = &app_state.db;
+
+    let mut tx = db.begin().await?;
+
+    let updated = quote::update(&mut tx, &json_quote).await?;
+
+    if !updated {
+        return Err(ApiError::NotFound(format!("no quote with id {}", quote_id)));
+    }
+
+    tx.commit().await?;
+    log::info!("quote {} updated by {}", quote_id, auth.claims.subject());
+
+    Ok(axum::Json(json_quote))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/quote/{quote_id}",
+    request_body = JsonQuotePatch,
+    security(("jwt" = ["editor"])),
+    responses(
+        (status = 200, description = "Quote updated successfully", body = JsonQuote),
+        (status = 422, description = "Invalid quote fields after patching", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Requires the editor role", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 404, description = "Quote not found", body = ApiError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn patch_quote(
+    State(app_state): State<SharedAppState>,
+    auth: Authorized<EditorRole>,
+    Path(quote_id): Path<String>,
+    axum::Json(patch): axum::Json<JsonQuotePatch>,
+) -> Result<impl IntoResponse, ApiError> {
+    let db = &app_state.db;
+
+    let mut tx = db.begin().await?;
+
+    // Load the current quote inside the transaction so the merge sees a consistent row:
+    let quote = sqlx::query_as!(Quote, "SELECT * FROM quotes WHERE id = ?;", quote_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("no quote with id {}", quote_id)))?;
+
+    let tags: Vec<String> =
+        sqlx::query_scalar!("SELECT tag FROM tags WHERE quote_id = ?;", quote_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+    let mut json_quote = JsonQuote::new(quote, tags);
+    patch.apply(&mut json_quote);
+    json_quote.validate().map_err(ApiError::Validation)?;
+
+    let updated = quote::update(&mut tx, &json_quote).await?;
+
+    if !updated {
+        return Err(ApiError::NotFound(format!("no quote with id {}", quote_id)));
+    }
+
+    tx.commit().await?;
+    log::info!("quote {} patched by {}", quote_id, auth.claims.subject());
+
+    Ok(axum::Json(json_quote))
+}
+
+// Default and maximum number of quotes returned by one page of /all-quotes:
+const DEFAULT_PAGE_LIMIT: u32 = 20;
+const MAX_PAGE_LIMIT: u32 = 100;
+
+// Query parameters for paging, filtering and sorting /all-quotes:
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListParams {
+    /// Maximum number of quotes to return (default 20, at most 100; no limit when streaming).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<u32>,
+    /// Number of quotes to skip before the first one returned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u32>,
+    /// Only return quotes by this author (case-insensitive).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    /// Only return quotes carrying this tag. Same as tags with a single tag.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    /// Only return quotes carrying these comma-separated tags, matched according to mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+    /// any (the default) or all of tags.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(inline)]
+    mode: Option<TagMatch>,
+    /// Leave out quotes carrying any of these comma-separated tags.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclude: Option<String>,
+    /// Sort order: id, author or length.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(inline)]
+    sort: Option<QuoteSort>,
+}
+
+// One page of quotes, with the total match count and a link to the next page:
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QuotePage {
+    total: i64,           // Number of quotes matching the filters.
+    limit: u32,           // Page size used for this page.
+    offset: u32,          // Offset of the first quote in this page.
+    next: Option<String>, // Link to the next page, if there is one.
+    quotes: Vec<JsonQuote>,
+}
+
 #[utoipa::path(
     get,
     path = "/all-quotes",
+    params(ListParams),
+    security(("jwt" = ["reader"])),
     responses(
-        (status = 200, description = "Get all quotes", body = [JsonQuote]),
-        (status = 400, description = "Bad request", body = String),
-        (status = 401, description = "Auth Error", body = authjwt::AuthError),
-        (status = 500, description = "Database error")
+        (status = 200, description = "Get a page of quotes, or with Accept: application/x-ndjson every matching quote, one per line", content(
+            (QuotePage = "application/json"),
+            (JsonQuote = "application/x-ndjson")
+        )),
+        (status = 400, description = "Bad request", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
     )
 )]
 pub async fn get_all_quotes(
-    State(app_state): State<Arc<RwLock<AppState>>>,
-    _claims: authjwt::Claims,
-) -> Result<impl IntoResponse, StatusCode> {
-    let app_reader = app_state.read().await;
-    let db = &app_reader.db;
-
-    let rows = sqlx::query!("SELECT * FROM quotes")
-        .fetch_all(db)
-        .await
-        .map_err(|e| {
-            log::error!("Failed to fetch quotes: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    State(app_state): State<SharedAppState>,
+    _auth: Authorized<ReaderRole>,
+    Query(params): Query<ListParams>,
+    headers: http::HeaderMap,
+) -> Result<response::Response, ApiError> {
+    let db = &app_state.db;
 
-    let mut quotes = Vec::new();
-
-    for row in rows {
-        let tags: Vec<String> =
-            sqlx::query_scalar!("SELECT tag FROM tags WHERE quote_id = ?;", row.id)
-                .fetch_all(db)
-                .await
-                .map_err(|e| {
-                    log::error!("Failed to fetch tags for quote {}: {}", row.id, e);
-                    StatusCode::INTERNAL_SERVER_ERROR
-                })?;
-
-        let quote = JsonQuote::new(
-            Quote {
-                id: row.id,
-                quote: row.quote,
-                author: row.author,
-            },
-            tags,
-        );
-
-        quotes.push(quote);
+    let offset = params.offset.unwrap_or(0);
+    let sort = params.sort.unwrap_or_default();
+    let tags = [params.tag.as_deref(), params.tags.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(",");
+    let filter = QuoteFilter {
+        author: params.author.clone(),
+        tags: TagFilter::new(Some(&tags), params.mode, params.exclude.as_deref()),
+    };
+
+    // NDJSON clients get every matching quote streamed, instead of a page:
+    let ndjson = headers
+        .get(http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(QuoteFormat::Ndjson.mime()));
+    if ndjson {
+        return Ok(stream_quotes(
+            db.clone(),
+            filter,
+            sort,
+            params.limit,
+            offset,
+        ));
     }
 
-    Ok((StatusCode::OK, axum::Json(quotes)))
-}
\ No newline at end of file
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let (quotes, total) = quote::list(db, &filter, sort, limit, offset).await?;
+
+    // Link to the following page with the same filters, unless this was the last one:
+    let next_offset = offset.saturating_add(limit);
+    let next = if i64::from(next_offset) < total {
+        let next_params = ListParams {
+            limit: Some(limit),
+            offset: Some(next_offset),
+            ..params
+        };
+        let query = serde_urlencoded::to_string(&next_params)
+            .map_err(|e| ApiError::BadRequest(format!("could not build next page link: {}", e)))?;
+        Some(format!("/api/v1/all-quotes?{}", query))
+    } else {
+        None
+    };
+
+    let page = QuotePage {
+        total,
+        limit,
+        offset,
+        next,
+        quotes,
+    };
+
+    Ok((StatusCode::OK, axum::Json(page)).into_response())
+}
+
+// The quotes matching a filter as an NDJSON body, written out as they are read from the
+// database. A database error cuts the body short, since the status has been sent by then:
+fn stream_quotes(
+    db: SqlitePool,
+    filter: QuoteFilter,
+    sort: QuoteSort,
+    limit: Option<u32>,
+    offset: u32,
+) -> response::Response {
+    use tokio_stream::StreamExt;
+
+    let lines = quote::stream(db, filter, sort, limit, offset).map(|json_quote| {
+        let mut writer = QuoteWriter::new(QuoteFormat::Ndjson, Vec::new());
+        json_quote
+            .map_err(std::io::Error::other)
+            .and_then(|json_quote| writer.write(&json_quote).map_err(std::io::Error::other))
+            .map(|()| std::mem::take(writer.get_mut()))
+            .inspect_err(|e| log::error!("quote listing failed: {}", e))
+    });
+    (
+        [(http::header::CONTENT_TYPE, QuoteFormat::Ndjson.mime())],
+        axum::body::Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+// Largest JSON array accepted by /import. NDJSON uploads are read a line at a time and
+// have no total limit, only a per-line one:
+const MAX_IMPORT_ARRAY_BYTES: usize = 16 * 1024 * 1024;
+const MAX_IMPORT_LINE_BYTES: usize = 64 * 1024;
+
+// Query parameters for /import:
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportParams {
+    /// insert (skip quotes whose id already exists, the default), upsert (overwrite them)
+    /// or replace (upsert, then delete every quote not in the upload).
+    #[param(inline)]
+    mode: Option<ImportMode>,
+    /// Only report what the import would do, without changing anything.
+    dry_run: Option<bool>,
+}
+
+// Log the totals of a finished import:
+fn log_import(user: &str, report: &ImportReport) {
+    log::info!(
+        "import by {}{}: {} inserted, {} updated, {} unchanged, {} skipped, {} failed, {} deleted",
+        user,
+        if report.dry_run { " (dry run)" } else { "" },
+        report.inserted,
+        report.updated,
+        report.unchanged,
+        report.skipped,
+        report.failed,
+        report.deleted
+    );
+}
+
+// Route created: /import
+// Load quotes from a JSON array or an NDJSON stream (one quote per line), told apart by the
+// first character of the upload. CSV, YAML and fortune uploads are recognized by their
+// Content-Type. Each record is validated and stored on its own, and the report says what
+// happened to every one of them.
+#[utoipa::path(
+    post,
+    path = "/import",
+    params(ImportParams),
+    request_body(
+        description = "A JSON array of quotes, NDJSON with one quote per line, CSV, YAML or fortune text",
+        content(
+            (Vec<JsonQuote> = "application/json"),
+            (JsonQuote = "application/x-ndjson"),
+            (String = "text/csv"),
+            (Vec<JsonQuote> = "application/yaml"),
+            (String = "text/plain")
+        )
+    ),
+    security(("jwt" = ["editor"])),
+    responses(
+        (status = 200, description = "Import finished; see the report for each record", body = ImportReport),
+        (status = 400, description = "Upload is not a JSON array or NDJSON, or is too large", body = ApiError, content_type = "application/problem+json"),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 403, description = "Requires the editor role, or admin for replace", body = authjwt::AuthError, content_type = "application/problem+json"),
+        (status = 500, description = "Database error", body = ApiError, content_type = "application/problem+json")
+    )
+)]
+pub async fn import_quotes(
+    State(app_state): State<SharedAppState>,
+    auth: Authorized<EditorRole>,
+    Query(params): Query<ImportParams>,
+    headers: http::HeaderMap,
+    body: axum::body::Body,
+) -> Result<impl IntoResponse, ApiError> {
+    use tokio_stream::StreamExt;
+
+    let mode = params.mode.unwrap_or_default();
+    // Replacing can delete quotes, which only admins may do:
+    if mode == ImportMode::Replace && auth.claims.role() < Role::Admin {
+        return Err(AuthError::Forbidden.into());
+    }
+    let mut importer = Importer::new(&app_state.db, mode, params.dry_run.unwrap_or(false));
+
+    // Formats other than JSON and NDJSON can't be read a record at a time, so they are
+    // read whole and then imported:
+    let format = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(QuoteFormat::from_mime);
+    if let Some(format @ (QuoteFormat::Csv | QuoteFormat::Yaml | QuoteFormat::Fortune)) = format {
+        let upload = axum::body::to_bytes(body, MAX_IMPORT_ARRAY_BYTES)
+            .await
+            .map_err(|e| ApiError::BadRequest(format!("could not read upload: {}", e)))?;
+        for json_quote in format.read(&upload[..])? {
+            importer.record(Ok(json_quote)).await;
+        }
+        let report = importer.finish().await?;
+        log_import(auth.claims.subject(), &report);
+        return Ok(axum::Json(report));
+    }
+
+    // Read the upload as it arrives. NDJSON lines are imported as soon as they are
+    // complete; a JSON array has to be read whole before it can be parsed.
+    let mut chunks = body.into_data_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut is_array = None;
+    while let Some(chunk) = chunks.next().await {
+        let chunk =
+            chunk.map_err(|e| ApiError::BadRequest(format!("could not read upload: {}", e)))?;
+        buffer.extend_from_slice(&chunk);
+
+        if is_array.is_none() {
+            is_array = buffer
+                .iter()
+                .find(|b| !b.is_ascii_whitespace())
+                .map(|&b| b == b'[');
+        }
+
+        match is_array {
+            Some(true) if buffer.len() > MAX_IMPORT_ARRAY_BYTES => {
+                return Err(ApiError::BadRequest(format!(
+                    "JSON array uploads are limited to {} bytes; send NDJSON instead",
+                    MAX_IMPORT_ARRAY_BYTES
+                )));
+            }
+            Some(false) => {
+                while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    importer.line(&line).await;
+                }
+                if buffer.len() > MAX_IMPORT_LINE_BYTES {
+                    return Err(ApiError::BadRequest(format!(
+                        "NDJSON lines are limited to {} bytes",
+                        MAX_IMPORT_LINE_BYTES
+                    )));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if is_array == Some(true) {
+        let records: Vec<serde_json::Value> = serde_json::from_slice(&buffer)
+            .map_err(|e| ApiError::BadRequest(format!("upload is not a JSON array: {}", e)))?;
+        for json in records {
+            importer.record(serde_json::from_value(json)).await;
+        }
+    } else {
+        importer.line(&buffer).await; // The last line may not end in a newline.
+    }
+
+    let report = importer.finish().await?;
+    log_import(auth.claims.subject(), &report);
+    Ok(axum::Json(report))
+}
+
+// Query parameters for /export:
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    /// json (the default), ndjson, csv, yaml or fortune.
+    #[param(inline)]
+    format: Option<QuoteFormat>,
+}
+
+// Route created: /export
+// Stream every quote with its tags in any of the formats /import and the import subcommand read.
+// Quotes are written out as they are read from the database.
+#[utoipa::path(
+    get,
+    path = "/export",
+    params(ExportParams),
+    security(("jwt" = ["reader"])),
+    responses(
+        (status = 200, description = "All quotes with their tags", content(
+            ([JsonQuote] = "application/json"),
+            (JsonQuote = "application/x-ndjson"),
+            (String = "text/csv"),
+            ([JsonQuote] = "application/yaml"),
+            (String = "text/plain")
+        )),
+        (status = 401, description = "Auth Error", body = authjwt::AuthError, content_type = "application/problem+json")
+    )
+)]
+pub async fn export_quotes(
+    State(app_state): State<SharedAppState>,
+    _auth: Authorized<ReaderRole>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    use tokio_stream::StreamExt;
+
+    let format = params.format.unwrap_or_default();
+    let db = app_state.db.clone();
+    let (sender, receiver) = tokio::sync::mpsc::channel::<std::io::Result<Vec<u8>>>(16);
+
+    // Produce the body on its own task; it stops early if the client goes away.
+    tokio::spawn(async move {
+        let mut quotes = std::pin::pin!(quote::export(&db));
+        let mut writer = QuoteWriter::new(format, Vec::new());
+        while let Some(json_quote) = quotes.next().await {
+            let written = match json_quote {
+                Ok(json_quote) => writer.write(&json_quote).map_err(std::io::Error::other),
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            let chunk = match written {
+                Ok(()) => Ok(std::mem::take(writer.get_mut())),
+                Err(e) => {
+                    log::error!("quote export failed: {}", e);
+                    Err(e)
+                }
+            };
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+        let end = writer.finish().map_err(std::io::Error::other);
+        let _ = sender.send(end).await;
+    });
+
+    let body = axum::body::Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(receiver));
+    let disposition = format!("attachment; filename=\"quotes.{}\"", format.extension());
+    (
+        [
+            (http::header::CONTENT_TYPE, format.mime().to_string()),
+            (http::header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+}
@@ -2,69 +2,347 @@
 
 use crate::http::StatusCode;
 use crate::*;
+use chrono::DateTime;
 use chrono::TimeDelta;
 use chrono::Utc;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::Algorithm;
+use std::str::FromStr;
 
-pub struct JwtKeys {
-    encoding: EncodingKey,
+// A signing key from the keyring, identified by the `kid` in token headers:
+struct JwtKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: Option<EncodingKey>, // None for retired keys kept only for verification.
     decoding: DecodingKey,
+    public_jwk: Option<Jwk>, // Published in the JWKS for asymmetric keys.
+    retired_at: Option<DateTime<Utc>>,
+}
+
+impl JwtKey {
+    // Whether tokens signed with this key should still be accepted:
+    fn verifies(&self, grace: TimeDelta) -> bool {
+        match self.retired_at {
+            Some(retired_at) => Utc::now() < retired_at + grace,
+            None => true,
+        }
+    }
+}
+
+pub struct JwtKeys {
+    keys: Vec<JwtKey>,
+    active: usize, // Index of the key new tokens are signed with.
+    // Retired keys still verify tokens for this long after retirement, so tokens signed
+    // just before a rotation keep working until they expire. It is the access token lifetime.
+    grace: TimeDelta,
 }
 
 impl JwtKeys {
-    pub fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
+    // A keyring holding just one HS512 secret, for setups without a keyring file:
+    pub fn new(secret: &[u8], grace: TimeDelta) -> Self {
+        let key = JwtKey {
+            kid: "default".to_string(),
+            algorithm: Algorithm::HS512,
+            encoding: Some(EncodingKey::from_secret(secret)),
             decoding: DecodingKey::from_secret(secret),
+            public_jwk: None,
+            retired_at: None,
+        };
+        Self {
+            keys: vec![key],
+            active: 0,
+            grace,
         }
     }
+
+    // The key new tokens are signed with:
+    fn active(&self) -> &JwtKey {
+        &self.keys[self.active]
+    }
+
+    // The kid of the signing key, and how many keys verify tokens, for config check:
+    pub fn summary(&self) -> (&str, usize) {
+        let verifying = self
+            .keys
+            .iter()
+            .filter(|key| key.verifies(self.grace))
+            .count();
+        (&self.active().kid, verifying)
+    }
+
+    // The key a token header asks to be verified with. Tokens without a kid are checked
+    // against the active key. Retired keys past their grace period are not used.
+    fn verification_key(&self, kid: Option<&str>) -> Option<&JwtKey> {
+        let key = match kid {
+            Some(kid) => self.keys.iter().find(|key| key.kid == kid)?,
+            None => self.active(),
+        };
+        key.verifies(self.grace).then_some(key)
+    }
+
+    // The public keys other services can verify our tokens with:
+    pub fn jwks(&self) -> JwkSet {
+        let keys = self
+            .keys
+            .iter()
+            .filter(|key| key.verifies(self.grace))
+            .filter_map(|key| key.public_jwk.clone())
+            .collect();
+        JwkSet { keys }
+    }
 }
 
-pub async fn read_secret(
-    env_var: &str,
-    default: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let secretf = std::env::var(env_var).unwrap_or_else(|_| default.to_owned());
-    let secret = tokio::fs::read_to_string(secretf).await?;
+pub async fn read_secret(path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    let secret = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
     Ok(secret.trim().to_string())
 }
 
-pub async fn make_jwt_keys() -> Result<JwtKeys, Box<dyn std::error::Error>> {
-    let secret = read_secret("JWT_SECRETFILE", "secrets/jwt_secret.txt").await?;
-    Ok(JwtKeys::new(secret.as_bytes()))
+// One key in the keyring file. HMAC keys name a secret file, RSA and EdDSA keys name PEM
+// files. A retired key may leave out its private key, since it only verifies tokens.
+#[derive(Deserialize)]
+struct KeySpec {
+    kid: String,
+    alg: Algorithm,
+    secret_file: Option<std::path::PathBuf>,
+    private_key_file: Option<std::path::PathBuf>,
+    public_key_file: Option<std::path::PathBuf>,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+// The keyring file, e.g. secrets/jwt_keys.json:
+//     {
+//       "active": "2026-10",
+//       "keys": [
+//         { "kid": "2026-10", "alg": "EdDSA",
+//           "private_key_file": "secrets/jwt_ed25519.pem",
+//           "public_key_file": "secrets/jwt_ed25519.pub.pem" },
+//         { "kid": "default", "alg": "HS512", "secret_file": "secrets/jwt_secret.txt",
+//           "retired_at": "2026-10-01T00:00:00Z" }
+//       ]
+//     }
+#[derive(Deserialize)]
+struct KeyringSpec {
+    active: String,
+    keys: Vec<KeySpec>,
+}
+
+// Load one keyring entry, reading its key files:
+async fn load_key(spec: KeySpec) -> Result<JwtKey, KeyError> {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, KeyAlgorithm, OctetKeyPairParameters,
+        OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    };
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::traits::PublicKeyParts;
+
+    async fn read(
+        kid: &str,
+        path: Option<&std::path::Path>,
+        what: &str,
+    ) -> Result<Vec<u8>, KeyError> {
+        let path =
+            path.ok_or_else(|| KeyError::MissingKeyFile(kid.to_string(), what.to_string()))?;
+        Ok(tokio::fs::read(path).await?)
+    }
+
+    let kid = spec.kid;
+    let common = CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(KeyAlgorithm::from_str(&format!("{:?}", spec.alg))?),
+        key_id: Some(kid.clone()),
+        ..Default::default()
+    };
+
+    let (encoding, decoding, public_jwk) = match spec.alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = read(&kid, spec.secret_file.as_deref(), "secret_file").await?;
+            let secret = String::from_utf8_lossy(&secret).trim().to_string();
+            let encoding = EncodingKey::from_secret(secret.as_bytes());
+            (
+                Some(encoding),
+                DecodingKey::from_secret(secret.as_bytes()),
+                None,
+            )
+        }
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {
+            let public_pem = read(&kid, spec.public_key_file.as_deref(), "public_key_file").await?;
+            let public_pem = String::from_utf8_lossy(&public_pem).to_string();
+            let public_key = rsa::RsaPublicKey::from_public_key_pem(&public_pem)
+                .map_err(|e| KeyError::InvalidKey(kid.clone(), e.to_string()))?;
+            let jwk = Jwk {
+                common,
+                algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+                    e: URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+                }),
+            };
+            let encoding = match spec.private_key_file {
+                Some(path) => Some(EncodingKey::from_rsa_pem(&tokio::fs::read(path).await?)?),
+                None => None,
+            };
+            (
+                encoding,
+                DecodingKey::from_rsa_pem(public_pem.as_bytes())?,
+                Some(jwk),
+            )
+        }
+        Algorithm::EdDSA => {
+            let public_pem = read(&kid, spec.public_key_file.as_deref(), "public_key_file").await?;
+            // An Ed25519 SubjectPublicKeyInfo ends with the 32 raw public key bytes.
+            let der = pem::parse(&public_pem)
+                .map_err(|e| KeyError::InvalidKey(kid.clone(), e.to_string()))?;
+            let raw = der.contents().last_chunk::<32>().ok_or_else(|| {
+                KeyError::InvalidKey(kid.clone(), "short Ed25519 key".to_string())
+            })?;
+            let jwk = Jwk {
+                common,
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(raw),
+                }),
+            };
+            let encoding = match spec.private_key_file {
+                Some(path) => Some(EncodingKey::from_ed_pem(&tokio::fs::read(path).await?)?),
+                None => None,
+            };
+            (encoding, DecodingKey::from_ed_pem(&public_pem)?, Some(jwk))
+        }
+        alg => return Err(KeyError::UnsupportedAlgorithm(kid, alg)),
+    };
+
+    Ok(JwtKey {
+        kid,
+        algorithm: spec.alg,
+        encoding,
+        decoding,
+        public_jwk,
+        retired_at: spec.retired_at,
+    })
+}
+
+// Load the keyring file. The configured jwt_active_kid, if set, overrides the active key
+// named in it:
+async fn load_keyring(
+    path: &std::path::Path,
+    config: &config::AuthConfig,
+) -> Result<JwtKeys, KeyError> {
+    let spec: KeyringSpec = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+    let active_kid = config.jwt_active_kid.clone().unwrap_or(spec.active);
+
+    let mut keys = Vec::with_capacity(spec.keys.len());
+    for key_spec in spec.keys {
+        keys.push(load_key(key_spec).await?);
+    }
+
+    let active = keys
+        .iter()
+        .position(|key| key.kid == active_kid)
+        .ok_or_else(|| KeyError::UnknownActiveKey(active_kid.clone()))?;
+    if keys[active].encoding.is_none() || keys[active].retired_at.is_some() {
+        return Err(KeyError::ActiveKeyCannotSign(active_kid));
+    }
+
+    Ok(JwtKeys {
+        keys,
+        active,
+        grace: config.access_token_lifetime(),
+    })
+}
+
+// Use the configured keyring file (default secrets/jwt_keys.json) if there is one,
+// otherwise fall back to a single HS512 secret: jwt_secret, or the one in jwt_secret_file.
+pub async fn make_jwt_keys(
+    config: &config::AuthConfig,
+) -> Result<JwtKeys, Box<dyn std::error::Error>> {
+    if tokio::fs::try_exists(&config.jwt_keyring).await? {
+        let keys = load_keyring(&config.jwt_keyring, config).await?;
+        log::info!("jwt keyring loaded: signing with {}", keys.active().kid);
+        return Ok(keys);
+    }
+    let secret = match &config.jwt_secret {
+        Some(secret) => secret.clone(),
+        None => read_secret(&config.jwt_secret_file).await?,
+    };
+    Ok(JwtKeys::new(
+        secret.as_bytes(),
+        config.access_token_lifetime(),
+    ))
 }
 
 #[derive(Debug, thiserror::Error, Serialize)]
 pub enum AuthError {
     #[error("invalid token")]
     InvalidToken,
+    #[error("expired token")]
+    ExpiredToken,
     #[error("internal error: token creation")]
     TokenCreation,
     #[error("registration error")]
     Registration,
+    #[error("email is already registered")]
+    EmailTaken,
+    #[error("wrong credentials")]
+    WrongCredentials,
+    #[error("account is disabled")]
+    AccountDisabled,
+    #[error("internal error: database")]
+    Database,
+    #[error("insufficient permissions")]
+    Forbidden,
 }
 
+impl From<UserError> for AuthError {
+    fn from(e: UserError) -> Self {
+        match e {
+            UserError::EmailTaken => AuthError::EmailTaken,
+            UserError::WrongCredentials => AuthError::WrongCredentials,
+            UserError::Disabled => AuthError::AccountDisabled,
+            UserError::PasswordHash(e) => {
+                log::error!("password hashing failed: {}", e);
+                AuthError::TokenCreation
+            }
+            UserError::Database(e) => {
+                log::error!("user database error: {}", e);
+                AuthError::Database
+            }
+        }
+    }
+}
+
+// AuthError is documented in OpenAPI as the problem body it turns into:
 impl utoipa::PartialSchema for AuthError {
     fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::Schema> {
-        serde_json::json!({
-            "status":"401","error":"wrong credentials"
-        })
-        .into()
+        <Problem as utoipa::PartialSchema>::schema()
     }
 }
 
-impl utoipa::ToSchema for AuthError {}
+impl utoipa::ToSchema for AuthError {
+    fn schemas(schemas: &mut Vec<(String, utoipa::openapi::RefOr<utoipa::openapi::Schema>)>) {
+        <Problem as utoipa::ToSchema>::schemas(schemas);
+    }
+}
 
 #[derive(Debug, Serialize, ToSchema)]
 pub struct AuthBody {
     access_token: String,
     token_type: String,
+    #[schema(example = 900)]
+    expires_in: i64, // Seconds until the access token expires.
+    refresh_token: String,
 }
 
 impl AuthBody {
-    fn new(access_token: String) -> Self {
+    fn new(access_token: String, refresh_token: String, lifetime: TimeDelta) -> Self {
         Self {
             access_token,
             token_type: "Bearer".to_string(),
+            expires_in: lifetime.num_seconds(),
+            refresh_token,
         }
     }
 }
@@ -82,7 +360,7 @@ impl axum::extract::FromRequestParts<SharedAppState> for Claims {
         parts: &mut http::request::Parts,
         state: &SharedAppState,
     ) -> Result<Self, Self::Rejection> {
-        use jsonwebtoken::{decode, Algorithm, Validation};
+        use jsonwebtoken::{decode, decode_header, errors::ErrorKind, Validation};
 
         // Extract the token from the authorization header
         let TypedHeader(Authorization(bearer)) = parts
@@ -90,67 +368,391 @@ impl axum::extract::FromRequestParts<SharedAppState> for Claims {
             .await
             .map_err(|_| AuthError::InvalidToken)?;
         // Decode the user data
-        let appstate = state.read().await;
-        let decoding_key = &appstate.jwt_keys.decoding;
-        let validation = Validation::new(Algorithm::HS512);
-        let result = decode::<Claims>(bearer.token(), decoding_key, &validation);
-        let token_data = result.map_err(|_| AuthError::Registration)?;
+        let jwt_keys = state.jwt_keys();
+        let header = decode_header(bearer.token()).map_err(|_| AuthError::InvalidToken)?;
+        let key = jwt_keys
+            .verification_key(header.kid.as_deref())
+            .ok_or(AuthError::InvalidToken)?;
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[&state.config.auth.issuer]);
+        let result = decode::<Claims>(bearer.token(), &key.decoding, &validation);
+        // Expired tokens are routine, so clients are told to refresh rather than log in again.
+        let token_data = result.map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => AuthError::ExpiredToken,
+            _ => AuthError::InvalidToken,
+        })?;
+
+        // Logged-out tokens stay on the revocation list until they expire.
+        let revoked = tokens::is_revoked(&state.db, &token_data.claims.jti)
+            .await
+            .map_err(|e| {
+                log::error!("token revocation lookup failed: {}", e);
+                AuthError::Database
+            })?;
+        if revoked {
+            return Err(AuthError::InvalidToken);
+        }
+
+        // The token is only good while its user still exists and is enabled.
+        let user = user::get_by_email(&state.db, &token_data.claims.sub)
+            .await
+            .map_err(|e| {
+                log::error!("user lookup failed: {}", e);
+                AuthError::Database
+            })?
+            .ok_or(AuthError::InvalidToken)?;
+        if user.disabled {
+            return Err(AuthError::AccountDisabled);
+        }
+        // A user who has been demoted since the token was issued must log in again.
+        if user.role < token_data.claims.role {
+            return Err(AuthError::InvalidToken);
+        }
+
         Ok(token_data.claims)
     }
 }
 
+// A role requirement for a route, checked by the Authorized extractor:
+pub trait RequiredRole {
+    const ROLE: Role;
+}
+
+pub struct ReaderRole;
+impl RequiredRole for ReaderRole {
+    const ROLE: Role = Role::Reader;
+}
+
+pub struct EditorRole;
+impl RequiredRole for EditorRole {
+    const ROLE: Role = Role::Editor;
+}
+
+pub struct AdminRole;
+impl RequiredRole for AdminRole {
+    const ROLE: Role = Role::Admin;
+}
+
+// Extractor for the claims of a valid token whose role is at least R::ROLE. Handlers take
+// e.g. `Authorized<EditorRole>` to require editor access; lesser roles get a 403.
+pub struct Authorized<R: RequiredRole> {
+    pub claims: Claims,
+    role: std::marker::PhantomData<R>,
+}
+
+impl<R: RequiredRole> axum::extract::FromRequestParts<SharedAppState> for Authorized<R> {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &SharedAppState,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
+        if claims.role < R::ROLE {
+            return Err(AuthError::Forbidden);
+        }
+        Ok(Self {
+            claims,
+            role: std::marker::PhantomData,
+        })
+    }
+}
+
+// Adds the bearer token security scheme to the OpenAPI document. Routes name the role
+// they need as the scope of their "jwt" security requirement.
+pub struct SecurityAddon;
+
+impl utoipa::Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "jwt",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some(
+                        "Token from /register or /login. Roles: reader < editor < admin.",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
+
+impl AuthError {
+    // The HTTP status, problem type slug and title for this error:
+    pub fn kind(&self) -> (StatusCode, &'static str, &'static str) {
+        match self {
+            AuthError::Registration => (
+                StatusCode::UNAUTHORIZED,
+                "registration",
+                "Invalid registration",
+            ),
+            AuthError::TokenCreation => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "token-creation",
+                "Token creation error",
+            ),
+            AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "invalid-token", "Invalid token"),
+            AuthError::ExpiredToken => (StatusCode::UNAUTHORIZED, "expired-token", "Expired token"),
+            AuthError::EmailTaken => (
+                StatusCode::CONFLICT,
+                "email-taken",
+                "Email is already registered",
+            ),
+            AuthError::WrongCredentials => (
+                StatusCode::UNAUTHORIZED,
+                "wrong-credentials",
+                "Wrong credentials",
+            ),
+            AuthError::AccountDisabled => (
+                StatusCode::FORBIDDEN,
+                "account-disabled",
+                "Account is disabled",
+            ),
+            AuthError::Database => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database",
+                "Database error",
+            ),
+            AuthError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Insufficient permissions",
+            ),
+        }
+    }
+}
+
+// Auth errors are sent as problem+json, like every other API error:
 impl IntoResponse for AuthError {
     fn into_response(self) -> axum::response::Response {
-        let (status, error_message) = match self {
-            AuthError::Registration => (StatusCode::UNAUTHORIZED, "Invalid registration"),
-            AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
-            AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token"),
-        };
-        let body = Json(serde_json::json!({
-            "status": status.as_u16(),
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        ApiError::from(self).into_response()
     }
 }
 
+// Shortest password accepted when signing up:
+const MIN_PASSWORD_LEN: usize = 8;
+
 #[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct Registration {
     #[schema(example = "John Smith")]
     full_name: String,
     #[schema(example = "johnsmith@example.org")]
     email: String,
-    #[schema(example = "password123")]
+    #[schema(example = "correct horse battery staple")]
+    password: String,
+}
+
+impl Registration {
+    pub fn new(full_name: &str, email: &str, password: &str) -> Self {
+        Self {
+            full_name: full_name.to_string(),
+            email: email.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    // Reject sign-ups with a blank name, an implausible email or a short password:
+    pub fn check(&self) -> Result<(), AuthError> {
+        let email_ok = self
+            .email
+            .split_once('@')
+            .is_some_and(|(name, host)| !name.is_empty() && !host.is_empty());
+        if self.full_name.trim().is_empty()
+            || !email_ok
+            || self.password.chars().count() < MIN_PASSWORD_LEN
+        {
+            return Err(AuthError::Registration);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct Login {
+    #[schema(example = "johnsmith@example.org")]
+    email: String,
+    #[schema(example = "correct horse battery staple")]
     password: String,
 }
 
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    #[schema(example = "3f1c...e9")]
+    refresh_token: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Claims {
     #[schema(example = "quote-server.po8.org")]
     iss: String,
-    #[schema(example = "John Smith <johnsmith@example.org>")]
+    #[schema(example = "johnsmith@example.org")]
     sub: String,
+    #[schema(example = "John Smith")]
+    name: String,
+    role: Role,
+    #[schema(example = "1717629166")]
+    iat: u64,
     #[schema(example = "1717630066")]
     exp: u64,
+    #[schema(example = "9b2e4c0f7d1a4e6b8c3f5a7d9e1b3c5f")]
+    jti: String,
 }
 
-pub fn make_jwt_token(
+// Create an account from a registration and give back tokens for it:
+pub async fn register_user(
     appstate: &AppState,
     registration: &Registration,
 ) -> Result<AuthBody, AuthError> {
-    use jsonwebtoken::{encode, Algorithm, Header};
+    registration.check()?;
+    let user = user::create(
+        &appstate.db,
+        registration.full_name.trim(),
+        registration.email.trim(),
+        &registration.password,
+        Role::Reader,
+    )
+    .await?;
+    issue_tokens(appstate, &user, None).await
+}
+
+// Check a user's email and password and give back tokens for them:
+pub async fn login_user(appstate: &AppState, login: &Login) -> Result<AuthBody, AuthError> {
+    let user = user::authenticate(&appstate.db, login.email.trim(), &login.password).await?;
+    issue_tokens(appstate, &user, None).await
+}
+
+// Trade a refresh token for a new access token and a new refresh token. The old refresh
+// token stops working. The new access token carries the user's current role.
+pub async fn refresh_user(
+    appstate: &AppState,
+    request: &RefreshRequest,
+) -> Result<AuthBody, AuthError> {
+    let db = &appstate.db;
+    let redeemed = tokens::redeem_refresh_token(db, &request.refresh_token)
+        .await
+        .map_err(|e| {
+            log::error!("refresh token lookup failed: {}", e);
+            AuthError::Database
+        })?;
+    let (email, family) = redeemed.ok_or(AuthError::InvalidToken)?;
+
+    let user = user::get_by_email(db, &email)
+        .await
+        .map_err(UserError::from)?
+        .ok_or(AuthError::InvalidToken)?;
+    if user.disabled {
+        return Err(AuthError::AccountDisabled);
+    }
+
+    issue_tokens(appstate, &user, Some(&family)).await
+}
+
+// Revoke the access token the request was made with and, if given, every refresh token
+// descended from the same login:
+pub async fn logout_user(
+    appstate: &AppState,
+    claims: &Claims,
+    request: Option<&RefreshRequest>,
+) -> Result<(), AuthError> {
+    let db = &appstate.db;
+    let database_error = |e: sqlx::Error| {
+        log::error!("token revocation failed: {}", e);
+        AuthError::Database
+    };
+
+    let expires_at = i64::try_from(claims.exp).unwrap_or(i64::MAX);
+    tokens::revoke_access_token(db, &claims.jti, expires_at)
+        .await
+        .map_err(database_error)?;
+    if let Some(request) = request {
+        tokens::revoke_refresh_family(db, &request.refresh_token)
+            .await
+            .map_err(database_error)?;
+    }
+    Ok(())
+}
+
+// Make a short-lived access token and a stored refresh token for a user:
+async fn issue_tokens(
+    appstate: &AppState,
+    user: &User,
+    family: Option<&str>,
+) -> Result<AuthBody, AuthError> {
+    let auth = &appstate.config.auth;
+    let access_token = make_jwt_token(&appstate.jwt_keys(), auth, user)?;
+    let expires_at = (Utc::now() + auth.refresh_token_lifetime()).timestamp();
+    let refresh_token = tokens::issue_refresh_token(&appstate.db, &user.email, family, expires_at)
+        .await
+        .map_err(|e| {
+            log::error!("refresh token creation failed: {}", e);
+            AuthError::TokenCreation
+        })?;
+    Ok(AuthBody::new(
+        access_token,
+        refresh_token,
+        auth.access_token_lifetime(),
+    ))
+}
+
+impl Claims {
+    // The email of the user the token was issued to:
+    pub fn subject(&self) -> &str {
+        &self.sub
+    }
 
-    if registration.password != appstate.reg_key {
-        return Err(AuthError::Registration);
+    // The role the user had when the token was issued:
+    pub fn role(&self) -> Role {
+        self.role
     }
+}
+
+pub fn make_jwt_token(
+    jwt_keys: &JwtKeys,
+    config: &config::AuthConfig,
+    user: &User,
+) -> Result<String, AuthError> {
+    mint_jwt_token(
+        jwt_keys,
+        &config.issuer,
+        user,
+        config.access_token_lifetime(),
+    )
+}
+
+// Sign an access token for a user that is valid for the given time:
+pub fn mint_jwt_token(
+    jwt_keys: &JwtKeys,
+    issuer: &str,
+    user: &User,
+    lifetime: TimeDelta,
+) -> Result<String, AuthError> {
+    use jsonwebtoken::{encode, Header};
 
-    let iss = "quote-server.po8.org".to_string();
-    let sub = format!("{} <{}>", registration.full_name, registration.email);
-    let exp = (Utc::now() + TimeDelta::days(1)).timestamp();
-    let exp = u64::try_from(exp).unwrap();
-    let claims = Claims { iss, sub, exp };
-    let header = Header::new(Algorithm::HS512);
-    let token = encode(&header, &claims, &appstate.jwt_keys.encoding)
-        .map_err(|_| AuthError::TokenCreation)?;
-    Ok(AuthBody::new(token))
+    let iss = issuer.to_string();
+    let sub = user.email.clone();
+    let name = user.full_name.clone();
+    let role = user.role;
+    let now = Utc::now();
+    let iat = u64::try_from(now.timestamp()).unwrap();
+    let exp = u64::try_from((now + lifetime).timestamp()).map_err(|_| AuthError::TokenCreation)?;
+    let jti = tokens::random_id();
+    let claims = Claims {
+        iss,
+        sub,
+        name,
+        role,
+        iat,
+        exp,
+        jti,
+    };
+    let key = jwt_keys.active();
+    let encoding = key.encoding.as_ref().ok_or(AuthError::TokenCreation)?;
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+    encode(&header, &claims, encoding).map_err(|_| AuthError::TokenCreation)
 }
@@ -0,0 +1,452 @@
+/*
+The command line interface. With no subcommand, or with 'serve', the binary runs the
+quote server. The other subcommands are one-shot admin tasks that exit when done:
+    1) import — load quotes from a file into the database.
+    2) export — write every quote to a file or stdout.
+    3) migrate up/down/status — apply, undo or list database migrations.
+    4) user add/disable — manage accounts without going through the API.
+    5) token mint — mint a JWT for an account offline, for scripts like auth-post.sh.
+    6) config check — print the effective configuration (see config.rs).
+Flags override the config file and the environment.
+ */
+use crate::*;
+use config::Config;
+
+use clap::{Args as ClapArgs, Subcommand};
+use sqlx::migrate::Migrate;
+use std::path::PathBuf;
+
+// Options shared by every subcommand, plus the serve options so that running the
+// binary with no subcommand still serves:
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Famous quote server",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Args {
+    /// Config file. Defaults to $QUOTE_SERVER_CONFIG, then quote-server.toml if there is one.
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Database uri. Defaults to database.uri in the config, $DATABASE_URL, then sqlite://db/quotes.db.
+    #[arg(short, long, name = "db-uri", global = true)]
+    pub db_uri: Option<String>,
+    #[command(flatten)]
+    pub serve: ServeArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the web page and the API (the default).
+    Serve(ServeArgs),
+    /// Load quotes from a file into the database.
+    Import(ImportArgs),
+    /// Write every quote in the database to a file, or to stdout.
+    Export(ExportArgs),
+    /// Apply, undo or list the database migrations.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateCommand,
+    },
+    /// Manage user accounts.
+    User {
+        #[command(subcommand)]
+        action: UserCommand,
+    },
+    /// Mint JWT access tokens.
+    Token {
+        #[command(subcommand)]
+        action: TokenCommand,
+    },
+    /// Inspect the configuration.
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(ClapArgs)]
+pub struct ServeArgs {
+    /// Port to listen on, unless --bind gives one [default: 8000].
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Address to listen on: a host or IP address, host:port, [ipv6]:port, or unix:/path/to/socket
+    /// [default: 127.0.0.1].
+    #[arg(short, long)]
+    pub bind: Option<String>,
+    /// PEM certificate chain to serve HTTPS with.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(ClapArgs)]
+pub struct ImportArgs {
+    /// File to read quotes from.
+    path: PathBuf,
+    /// Format of the file. Guessed from its extension if not given.
+    #[arg(short, long, value_enum)]
+    format: Option<QuoteFormat>,
+    /// What to do with quotes already in the database.
+    #[arg(short, long, value_enum, default_value_t)]
+    mode: ImportMode,
+    /// Check the file and show what would change, without writing.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(ClapArgs)]
+pub struct ExportArgs {
+    /// File to write the quotes to. Stdout if not given.
+    path: Option<PathBuf>,
+    /// Format to write. Guessed from the file extension if not given.
+    #[arg(short, long, value_enum)]
+    format: Option<QuoteFormat>,
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+    /// Apply every pending migration.
+    Up,
+    /// Undo the latest migration, or every migration after --to.
+    Down {
+        /// Version to go back to; 0 undoes them all.
+        #[arg(long)]
+        to: Option<i64>,
+    },
+    /// List the migrations and whether each one is applied.
+    Status,
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create an account. The password is read from --password-file, or from stdin.
+    Add {
+        email: String,
+        /// Full name of the user.
+        #[arg(short, long)]
+        name: String,
+        /// Role of the new account (defaults to reader).
+        #[arg(short, long, value_enum)]
+        role: Option<Role>,
+        /// File holding the password.
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Disable an account so it can no longer log in or use its tokens.
+    Disable { email: String },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Mint an access token for an account, signed with the active JWT key.
+    Mint {
+        email: String,
+        /// How long the token is valid, 1 to 1440 minutes. Defaults to auth.access_token_minutes.
+        #[arg(long, value_parser = clap::value_parser!(i64).range(1..=config::MAX_ACCESS_TOKEN_MINUTES))]
+        minutes: Option<i64>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Check the configuration, its key files and certificates, and print it with secrets redacted.
+    Check,
+}
+
+// Override the config with the flags given on the command line:
+pub fn apply_flags(config: &mut Config, db_uri: Option<String>, command: &Command) {
+    if let Some(db_uri) = db_uri {
+        config.database.uri = db_uri;
+        config.sources.push("--db-uri".to_string());
+    }
+    if let Command::Serve(args) = command {
+        let server = &mut config.server;
+        let mut flags = Vec::new();
+        if let Some(port) = args.port {
+            server.port = port;
+            flags.push("--port");
+        }
+        if let Some(bind) = &args.bind {
+            server.bind = bind.clone();
+            flags.push("--bind");
+        }
+        if args.tls_cert.is_some() {
+            server.tls_cert = args.tls_cert.clone();
+            server.tls_key = args.tls_key.clone();
+            flags.push("--tls-cert");
+        }
+        config.sources.extend(flags.into_iter().map(str::to_string));
+    }
+}
+
+// Run one of the admin subcommands against the database:
+pub async fn run(command: Command, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let db_uri = &config.database.uri;
+    match command {
+        Command::Serve(_) => unreachable!("serve is handled by main"),
+        Command::Config { action } => check_config(config, action).await,
+        Command::Migrate { action } => migrate(&open_db(db_uri).await?, action).await,
+        command => {
+            let db = open_db(db_uri).await?;
+            sqlx::migrate!().run(&db).await?;
+            match command {
+                Command::Import(args) => import(&db, args).await,
+                Command::Export(args) => export(&db, args).await,
+                Command::User { action } => manage_user(&db, action).await,
+                Command::Token { action } => token(&db, &config.auth, action).await,
+                Command::Serve(_) | Command::Config { .. } | Command::Migrate { .. } => {
+                    unreachable!()
+                }
+            }
+        }
+    }
+}
+
+// Load quotes from a file and report what was done. Exits non-zero if any record failed:
+async fn import(db: &SqlitePool, args: ImportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let quotes = read_quotes(&args.path, args.format)?;
+    let mut importer = Importer::new(db, args.mode, args.dry_run);
+    for qu in quotes {
+        importer.record(Ok(qu)).await;
+    }
+    let report = importer.finish().await?;
+    print_import_report(&report, args.mode);
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Print what an import did: every quote that failed, changed, was skipped or deleted,
+// then the totals:
+fn print_import_report(report: &ImportReport, mode: ImportMode) {
+    let would = if report.dry_run { "would be " } else { "" };
+    for result in &report.results {
+        let id = result.id.as_deref().unwrap_or("-");
+        match result.status {
+            ImportStatus::Failed => {
+                let detail = result.detail.as_deref().unwrap_or("failed");
+                eprintln!("error: record {} ({}): {}", result.record, id, detail);
+                for e in &result.errors {
+                    eprintln!("    {}: {}", e.field, e.message);
+                }
+            }
+            ImportStatus::Updated | ImportStatus::Skipped => {
+                let status = if result.status == ImportStatus::Updated {
+                    "updated"
+                } else {
+                    "skipped"
+                };
+                println!("{} {}{}:", id, would, status);
+                for change in &result.changes {
+                    println!("    {}", change);
+                }
+            }
+            _ => (),
+        }
+    }
+    for id in &report.deleted_ids {
+        println!("{} {}deleted", id, would);
+    }
+    if mode == ImportMode::Replace && report.failed > 0 {
+        eprintln!("not deleting quotes missing from the file, because some records failed");
+    }
+
+    println!(
+        "{}{} inserted, {} updated, {} unchanged, {} skipped, {} failed, {} deleted",
+        if report.dry_run { "dry run: " } else { "" },
+        report.inserted,
+        report.updated,
+        report.unchanged,
+        report.skipped,
+        report.failed,
+        report.deleted
+    );
+}
+
+// Write every quote to a file, or stdout, a quote at a time:
+async fn export(db: &SqlitePool, args: ExportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use tokio_stream::StreamExt;
+
+    let format = args
+        .format
+        .or_else(|| args.path.as_ref().and_then(QuoteFormat::from_path))
+        .unwrap_or_default();
+    let out: Box<dyn Write> = match &args.path {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    let mut writer = QuoteWriter::new(format, std::io::BufWriter::new(out));
+    let mut quotes = std::pin::pin!(quote::export(db));
+    let mut count = 0;
+    while let Some(json_quote) = quotes.next().await {
+        writer.write(&json_quote?)?;
+        count += 1;
+    }
+    writer.finish()?.flush()?;
+
+    if let Some(path) = &args.path {
+        eprintln!("exported {} quotes to {}", count, path.display());
+    }
+    Ok(())
+}
+
+// Apply, undo or list the migrations:
+async fn migrate(
+    db: &SqlitePool,
+    action: MigrateCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let migrator = sqlx::migrate!();
+    match action {
+        MigrateCommand::Up => migrator.run(db).await?,
+        MigrateCommand::Down { to } => {
+            let target = match to {
+                Some(to) => to,
+                None => {
+                    // Go back to the migration before the latest applied one:
+                    let mut applied = applied_versions(db).await?;
+                    applied.pop();
+                    applied.pop().unwrap_or(0)
+                }
+            };
+            migrator.undo(db, target).await?;
+        }
+        MigrateCommand::Status => (),
+    }
+
+    let applied = applied_versions(db).await?;
+    for migration in migrator
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+    {
+        let status = if applied.contains(&migration.version) {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!(
+            "{:04} {:<20} {}",
+            migration.version, migration.description, status
+        );
+    }
+    Ok(())
+}
+
+// The versions of the applied migrations, oldest first:
+async fn applied_versions(db: &SqlitePool) -> Result<Vec<i64>, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let mut versions: Vec<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+// Add or disable an account:
+async fn manage_user(
+    db: &SqlitePool,
+    action: UserCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        UserCommand::Add {
+            email,
+            name,
+            role,
+            password_file,
+        } => {
+            let password = match password_file {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => {
+                    eprint!("Password for {}: ", email);
+                    let mut password = String::new();
+                    std::io::stdin().read_line(&mut password)?;
+                    password
+                }
+            };
+            let password = password.trim_end_matches(['\r', '\n']);
+
+            authjwt::Registration::new(&name, &email, password).check()?;
+            let role = role.unwrap_or(Role::Reader);
+            let user = user::create(db, &name, &email, password, role).await?;
+            println!(
+                "added {} as {}",
+                user.email,
+                format!("{:?}", user.role).to_lowercase()
+            );
+        }
+        UserCommand::Disable { email } => {
+            let user = user::update(db, &email, None, Some(true))
+                .await?
+                .ok_or_else(|| format!("no user with email {}", email))?;
+            println!("disabled {}", user.email);
+        }
+    }
+    Ok(())
+}
+
+// Mint an access token for an enabled account and print it:
+async fn token(
+    db: &SqlitePool,
+    auth: &config::AuthConfig,
+    action: TokenCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        TokenCommand::Mint { email, minutes } => {
+            let user = user::get_by_email(db, &email)
+                .await?
+                .ok_or_else(|| format!("no user with email {}", email))?;
+            if user.disabled {
+                return Err(authjwt::AuthError::AccountDisabled.into());
+            }
+            let jwt_keys = authjwt::make_jwt_keys(auth).await?;
+            let lifetime = match minutes {
+                Some(minutes) => chrono::TimeDelta::minutes(minutes),
+                None => auth.access_token_lifetime(),
+            };
+            println!(
+                "{}",
+                authjwt::mint_jwt_token(&jwt_keys, &auth.issuer, &user, lifetime)?
+            );
+        }
+    }
+    Ok(())
+}
+
+// The config has already been validated by main. Also load the JWT keys and the TLS
+// certificate it names, then print where the settings came from and the settings
+// themselves, with secrets redacted:
+async fn check_config(
+    config: &Config,
+    action: ConfigCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ConfigCommand::Check => {
+            let jwt_keys = authjwt::make_jwt_keys(&config.auth).await?;
+            if let (Some(cert), Some(key)) = (&config.server.tls_cert, &config.server.tls_key) {
+                tls::load_config(cert, key)?;
+            }
+
+            let mut sources = vec!["defaults".to_string()];
+            sources.extend(config.sources.iter().cloned());
+            println!("# settings from: {}", sources.join(", "));
+            let (active_kid, verifying) = jwt_keys.summary();
+            println!(
+                "# jwt keys: signing with {}, {} key(s) verifying",
+                active_kid, verifying
+            );
+            print!("{}", toml::to_string(&config.redacted())?);
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,224 @@
 extern crate serde_json;
 use thiserror::Error;
 
+use crate::authjwt::AuthError;
+use crate::*;
+
 #[derive(Debug, Error)]
 pub enum QuoteError {
     #[error("could not find quote file: {0}")]
     QuotesNotFound(#[from] std::io::Error),
     #[error("could not read quote file: {0}")]
     QuoteMisformat(#[from] serde_json::Error),
+    #[error("could not read or write CSV quotes: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("could not read or write YAML quotes: {0}")]
+    Yaml(#[from] serde_yaml::Error),
     #[error("invalid database uri: {0}")]
     InvalidDbUri(String),
 }
+
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("email is already registered")]
+    EmailTaken,
+    #[error("wrong email or password")]
+    WrongCredentials,
+    #[error("account is disabled")]
+    Disabled,
+    #[error("password hashing failed: {0}")]
+    PasswordHash(String),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("could not read key file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse keyring: {0}")]
+    Keyring(#[from] serde_json::Error),
+    #[error("invalid key: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("key {0}: missing {1}")]
+    MissingKeyFile(String, String),
+    #[error("key {0}: {1}")]
+    InvalidKey(String, String),
+    #[error("key {0}: unsupported algorithm {1:?}")]
+    UnsupportedAlgorithm(String, jsonwebtoken::Algorithm),
+    #[error("active key {0} is not in the keyring")]
+    UnknownActiveKey(String),
+    #[error("active key {0} is retired or has no private key")]
+    ActiveKeyCannotSign(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("could not parse config file {0}: {1}")]
+    Parse(String, toml::de::Error),
+    #[error("bad value in ${0}: {1}")]
+    Env(String, String),
+    #[error("invalid configuration:\n    {}", .0.join("\n    "))]
+    Invalid(Vec<String>),
+}
+
+// One invalid field of a request, reported in the "errors" list of a problem:
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    #[schema(example = "author")]
+    pub field: String,
+    #[schema(example = "must not be blank")]
+    pub message: String,
+}
+
+// The error type of every API endpoint. It is sent to the client as an RFC 7807
+// problem+json body, see Problem below.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("validation failed")]
+    Validation(Vec<FieldError>),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("unavailable: {0}")]
+    Unavailable(String),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("database error: {0}")]
+    Database(sqlx::Error),
+    #[error(transparent)]
+    Quote(#[from] QuoteError),
+}
+
+// Missing rows are 404s and unique key clashes are 409s; anything else is a server error:
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => ApiError::NotFound("no such record".to_string()),
+            sqlx::Error::Database(ref dbe) if dbe.is_unique_violation() => {
+                ApiError::Conflict("a record with that id already exists".to_string())
+            }
+            e => ApiError::Database(e),
+        }
+    }
+}
+
+// An RFC 7807 problem details body:
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "/problems/not-found")]
+    pub problem_type: String, // Identifies the kind of problem.
+    #[schema(example = "Not Found")]
+    pub title: String, // Short summary of the kind of problem.
+    #[schema(example = 404)]
+    pub status: u16, // The HTTP status code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "no quote with id 42")]
+    pub detail: Option<String>, // What went wrong this time.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>, // Invalid fields, for validation problems.
+}
+
+impl ApiError {
+    // The HTTP status, problem type slug and title for this error:
+    fn kind(&self) -> (http::StatusCode, &'static str, &'static str) {
+        use http::StatusCode;
+
+        match self {
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not-found", "Not Found"),
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad-request", "Bad Request"),
+            ApiError::Validation(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation",
+                "Validation Failed",
+            ),
+            ApiError::Conflict(_) => (StatusCode::CONFLICT, "conflict", "Conflict"),
+            ApiError::Unavailable(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Service Unavailable",
+            ),
+            ApiError::Auth(e) => e.kind(),
+            ApiError::Database(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database",
+                "Database Error",
+            ),
+            ApiError::Quote(QuoteError::InvalidDbUri(_)) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "configuration",
+                "Configuration Error",
+            ),
+            ApiError::Quote(_) => (StatusCode::BAD_REQUEST, "quote-format", "Unreadable Quotes"),
+        }
+    }
+
+    // The problem body for this error. Server-side failures are logged here and only
+    // described vaguely to the client.
+    pub fn problem(&self) -> Problem {
+        let (status, slug, title) = self.kind();
+
+        let detail = if let ApiError::Unavailable(detail) = self {
+            // Says what the server is waiting for, which is no secret.
+            log::warn!("{}", self);
+            Some(detail.clone())
+        } else if status.is_server_error() {
+            log::error!("{}", self);
+            None
+        } else {
+            log::warn!("{}", self);
+            match self {
+                ApiError::NotFound(detail)
+                | ApiError::BadRequest(detail)
+                | ApiError::Conflict(detail) => Some(detail.clone()),
+                ApiError::Validation(_) => Some("one or more fields are invalid".to_string()),
+                e => Some(e.to_string()),
+            }
+        };
+
+        let errors = match self {
+            ApiError::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+
+        Problem {
+            problem_type: format!("/problems/{}", slug),
+            title: title.to_string(),
+            status: status.as_u16(),
+            detail,
+            errors,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        if let ApiError::Auth(e) = &self {
+            metrics::auth_failure(e);
+        }
+        let problem = self.problem();
+        let status = http::StatusCode::from_u16(problem.status)
+            .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+        let content_type = [(http::header::CONTENT_TYPE, "application/problem+json")];
+        (status, content_type, Json(problem)).into_response()
+    }
+}
+
+// ApiError is documented in OpenAPI as the problem body it turns into:
+impl utoipa::PartialSchema for ApiError {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::Schema> {
+        <Problem as utoipa::PartialSchema>::schema()
+    }
+}
+
+impl utoipa::ToSchema for ApiError {
+    fn schemas(schemas: &mut Vec<(String, utoipa::openapi::RefOr<utoipa::openapi::Schema>)>) {
+        <Problem as utoipa::ToSchema>::schemas(schemas);
+    }
+}
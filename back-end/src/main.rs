@@ -1,23 +1,32 @@
 /*
 This Rust backend application uses the Axum framework to serve a quote API with the following features:
     1) Fetching random, themed, or ID-specific quotes.
-    2) Inserting quotes and tags from a file into a SQLite database.
-    3) JWT-based user authentication and registration.
+    2) Importing and exporting quotes and tags between files and a SQLite database.
+    3) JWT-based user authentication with registered accounts.
     4) Auto-generated Swagger/OpenAPI documentation.
-    5) Secure and observable with CORS and tracing support.
+    5) Secure and observable with CORS, tracing and Prometheus metrics, served over TCP, HTTPS or a Unix socket.
+    6) Admin subcommands for migrations, accounts and tokens (see cli.rs).
+    7) Layered configuration from a TOML file, the environment and flags (see config.rs).
 */
 
 mod api;         // REST API route handlers and OpenAPI docs
 mod authjwt;     // JWT key generation, encoding, decoding
+mod cli;         // Command line arguments and admin subcommands
+mod config;      // Settings from the config file, environment and command line
 mod error;       // Custom error types
+mod metrics;     // Prometheus metrics
 mod quote;       // Quote models and DB logic
 mod templates;   // HTML rendering
+mod tls;         // HTTPS listener
+mod tokens;      // Refresh tokens and access token revocation
+mod user;        // User accounts and password hashing
 mod web;         // HTML handler endpoints
 
 // The rest of the imports are the same as Bart's main.rs file:
 use error::*;
 use quote::*;
 use templates::*;
+use user::{Role, User, UserInfo};
 
 extern crate log;
 extern crate mime;
@@ -35,76 +44,53 @@ use axum_extra::{
     TypedHeader,
 };
 
+use arc_swap::ArcSwap;
 use clap::Parser;
 use jsonwebtoken::{DecodingKey, EncodingKey};
 extern crate fastrand;
 use serde::{Deserialize, Serialize};
-use sqlx::{migrate::MigrateDatabase, sqlite, Row, SqlitePool};
-use tokio::{net, sync::RwLock};
+use sqlx::{migrate::MigrateDatabase, sqlite, SqlitePool};
+use tokio::net;
 use tower_http::trace;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use utoipa::{OpenApi, ToSchema};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use utoipa_axum::{router::OpenApiRouter, routes};
 use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
-use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
-// Create the Args struct for the command line interface.
-// Useful for parsing flags such as '--init_from', '--db_uri', '--port'
-// and sets their defaults here:
-#[derive(Parser)] // Parses the cli argumnets or flags.
-struct Args {
-    #[arg(short, long, name = "init-from")]
-    init_from: Option<std::path::PathBuf>, // Optional path to initialize database path.
-    #[arg(short, long, name = "db-uri")]
-    db_uri: Option<String>, // Optional database uri.
-    #[arg(short, long, default_value = "8000")]
-    port: u16, // Default port of 8000
-}
-
-// The struct that holds the current quote and the database connection pool.
-// This is a shared resource for the application. It is protected view the
-// Rwlock type.
+// The struct that holds the database connection pool, the JWT keys and the configuration.
+// This is a shared resource for the application. There is no lock around it:
+// the pool is already safe to share, and the JWT keys can be swapped out
+// atomically when they are reloaded, so handlers never wait on each other.
 struct AppState {
     db: SqlitePool,
-    jwt_keys: authjwt::JwtKeys,
-    reg_key: String,
-    current_quote: Quote, // Thec current quote for the initial display.
+    jwt_keys: ArcSwap<authjwt::JwtKeys>,
+    config: config::Config,
 }
-type SharedAppState = Arc<RwLock<AppState>>;
+type SharedAppState = Arc<AppState>;
 impl AppState {
-    pub fn new(db: SqlitePool, jwt_keys: authjwt::JwtKeys, reg_key: String) -> Self {
-        // The default quote displayed on the page before any other quote is displayed:
-        let current_quote = Quote {
-            id: "101".to_string(),
-            quote: "Yesterday is history, tomorrow is a mystery, and today is a gift, that's why it's called the present.".to_string(),
-            author: "Turtle".to_string(),
-        };
+    pub fn new(db: SqlitePool, jwt_keys: authjwt::JwtKeys, config: config::Config) -> Self {
         Self {
             db,
-            jwt_keys,
-            reg_key,
-            current_quote,
+            jwt_keys: ArcSwap::from_pointee(jwt_keys),
+            config,
         }
     }
-}
 
-// Method to get the db_uri from the exported env variable.
-// Can be set by:
-//  1) $ export DATABASE_URL=sqlite://db/quotes.db
-//  2) .env:
-//      DATABASE_URL=sqlite://db/quotes.db
-//  3) Or, if neither is used, the default is 'sqlite://db/quotes.db'.
-fn get_db_uri(db_uri: Option<&str>) -> Cow<str> {
-    if let Some(db_uri) = db_uri {
-        db_uri.into()
-    } else if let Ok(db_uri) = std::env::var("DATABASE_URL") {
-        db_uri.into()
-    } else {
-        "sqlite://db/quotes.db".into()
+    // The current JWT keys. Holding on to them keeps them alive across a reload.
+    pub fn jwt_keys(&self) -> Arc<authjwt::JwtKeys> {
+        self.jwt_keys.load_full()
+    }
+
+    // Re-read the JWT keyring or secret file and start using it for new requests.
+    pub async fn reload_jwt_keys(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let jwt_keys = authjwt::make_jwt_keys(&self.config.auth).await?;
+        self.jwt_keys.store(Arc::new(jwt_keys));
+        Ok(())
     }
 }
 
@@ -127,90 +113,69 @@ fn extract_db_dir(db_uri: &str) -> Result<&str, QuoteError> {
     }
 }
 
-async fn serve() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse(); // Parse the cli arguments and flags.
-
-    // Get the database uri.
-    let db_uri: Cow<'_, str> = get_db_uri(args.db_uri.as_deref());
-
+// Open the database at the given uri (database.uri in the config), creating it if it does
+// not exist yet:
+async fn open_db(db_uri: &str) -> Result<SqlitePool, Box<dyn std::error::Error>> {
     // Check that the database uri exists. Otherwise, create it:
-    if !sqlite::Sqlite::database_exists(&db_uri).await? {
-        let db_dir = extract_db_dir(&db_uri)?;
+    if !sqlite::Sqlite::database_exists(db_uri).await? {
+        let db_dir = extract_db_dir(db_uri)?;
         std::fs::create_dir_all(db_dir)?;
-        sqlite::Sqlite::create_database(&db_uri).await?
+        sqlite::Sqlite::create_database(db_uri).await?
     }
 
-    // Connect to the database through the uri:/A
-    let db = SqlitePool::connect(&db_uri).await?;
+    // Connect to the database through the uri:
+    Ok(SqlitePool::connect(db_uri).await?)
+}
+
+async fn serve(config: config::Config) -> Result<(), Box<dyn std::error::Error>> {
+    let db = open_db(&config.database.uri).await?;
     sqlx::migrate!().run(&db).await?; // Run the migrations in the migrations dir.
 
-    // If a path is given with the '--init_form' command, then load the quotes
-    // from that file into the database:
-    if let Some(path) = args.init_from {
-        let quotes = read_quotes(path)?;
-        'next_quote: for qu in quotes {
-            let mut qtx = db.begin().await?;
-            let (q, ts) = qu.to_quote();
-
-            let quote_insert = sqlx::query!(
-                "insert into quotes (id, quote, author) values($1, $2, $3);",
-                q.id,
-                q.quote,
-                q.author,
-            )
-            .execute(&mut *qtx)
-            .await;
-
-            if let Err(e) = quote_insert {
-                eprintln!("error: quote insert: {}: {}", q.id.clone(), e);
-                qtx.rollback().await?;
-                continue;
-            };
-
-            for t in ts {
-                let tag_insert =
-                    sqlx::query!("insert into tags (quote_id, tag) values ($1, $2);", q.id, t,)
-                        .execute(&mut *qtx)
-                        .await;
-
-                if let Err(e) = tag_insert {
-                    eprintln!("error: tag insert: {} {}: {}", q.id.clone(), t, e);
-                    qtx.rollback().await?;
-                    continue 'next_quote;
-                };
+    let jwt_keys = authjwt::make_jwt_keys(&config.auth).await?;
+
+    // Load the certificate first, so a bad one fails before anything is bound:
+    let server = config.server.clone();
+    let tls_config = match (&server.tls_cert, &server.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        _ => None,
+    };
+    let allow_origin = config.cors.allow_origin();
+
+    // Initialize the app state object with the db pool, the jwt keys and the config.
+    // Keep a handle on the pool, to close it once the server has shut down.
+    let app_state = AppState::new(db.clone(), jwt_keys, config);
+
+    // Make the state sharable between the handlers.
+    let state = Arc::new(app_state);
+
+    // Initialize logging and tracing. The log filter only applies to the log output, so
+    // the query events the metrics are taken from reach metrics::QueryLayer regardless:
+    let log_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "quote-server=debug,info".into());
+    let query_filter = tracing_subscriber::filter::Targets::new()
+        .with_target("sqlx::query", tracing::Level::TRACE);
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(log_filter))
+        .with(metrics::QueryLayer.with_filter(query_filter))
+        .init();
+
+    // Reload the JWT keys on SIGHUP, so keys can be rotated without a restart:
+    let reload_state = state.clone();
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+            tracing::warn!("could not listen for SIGHUP: jwt key reload disabled");
+            return;
+        };
+        while hangups.recv().await.is_some() {
+            match reload_state.reload_jwt_keys().await {
+                Ok(()) => tracing::info!("jwt keys reloaded"),
+                Err(e) => tracing::error!("jwt key reload failed, keeping old keys: {}", e),
             }
-            qtx.commit().await?;
         }
-        return Ok(());
-    }
-
-    let jwt_keys = authjwt::make_jwt_keys().await.unwrap_or_else(|_| {
-        tracing::error!("jwt keys");
-        std::process::exit(1);
     });
 
-    let reg_key = authjwt::read_secret("REG_PASSWORD", "secrets/reg_password.txt")
-        .await
-        .unwrap_or_else(|_| {
-            tracing::error!("reg password");
-            std::process::exit(1);
-        });
-
-    // Initialize the app state object with the db pool and the initial quote.
-    let app_state = AppState::new(db, jwt_keys, reg_key);
-
-    // Make the state sharable for async reading and writing.
-    let state = Arc::new(RwLock::new(app_state));
-
-    // Initialize logging and tracing:
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "quote-server=debug,info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
     // https://carlosmv.hashnode.dev/adding-logging-and-tracing-to-an-axum-app-rust
     let trace_layer = trace::TraceLayer::new_for_http()
         .make_span_with(trace::DefaultMakeSpan::new().level(tracing::Level::INFO))
@@ -218,8 +183,14 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
 
     // Get log:
     let cors = tower_http::cors::CorsLayer::new()
-        .allow_methods([http::Method::GET, http::Method::POST, http::Method::DELETE])
-        .allow_origin(tower_http::cors::Any);
+        .allow_methods([
+            http::Method::GET,
+            http::Method::POST,
+            http::Method::PUT,
+            http::Method::PATCH,
+            http::Method::DELETE,
+        ])
+        .allow_origin(allow_origin);
 
     // Page not found:
     async fn handler_404() -> axum::response::Response {
@@ -228,10 +199,14 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
 
     // API available under url ../api/v1.
     // Serve the api endpoints there.
-    let (api_router, api) = OpenApiRouter::with_openapi(api::ApiDoc::openapi())
+    let (api_router, mut api) = OpenApiRouter::with_openapi(api::ApiDoc::openapi())
         .nest("/api/v1", api::router())
         .split_for_parts();
 
+    // The probes are documented with the API, but routed outside the trace layer below:
+    let (probe_router, probe_api) = api::probe_router().split_for_parts();
+    api.merge(probe_api);
+
     let swagger_ui = SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", api.clone());
     let redoc_ui = Redoc::with_url("/redoc", api);
     let rapidoc_ui = RapiDoc::new("/api-docs/openapi.json").path("/rapidoc");
@@ -239,30 +214,122 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
     // Build the app router. Connections to the styling, favicon, static files, etc.
     let app = axum::Router::new()
         .route("/", routing::get(web::get_quote))
+        .route("/.well-known/jwks.json", routing::get(api::jwks))
         .merge(swagger_ui)
         .merge(redoc_ui)
         .merge(rapidoc_ui)
         .merge(api_router)
         .fallback(handler_404)
         .layer(cors)
+        .layer(axum::middleware::from_fn(metrics::track_requests))
         .layer(trace_layer)
+        .merge(probe_router)
         .with_state(state);
 
-    // Set up the address information:
-    let localhost = "127.0.0.1";
-    let address = format!("{}:{}", localhost, args.port);
-
-    // Set up the tcp listener with the args.port default to 8000:
-    let listener = net::TcpListener::bind(&format!("{}:{}", localhost, args.port)).await?;
-    println!("Server running at http://{}", address);
+    if let Some(path) = server.bind.strip_prefix("unix:") {
+        remove_stale_socket(path)?;
+        let listener = net::UnixListener::bind(path)?;
+        println!("Server running at unix:{}", path);
+        run_server(listener, app).await?;
+        remove_stale_socket(path)?;
+    } else {
+        let listener = net::TcpListener::bind(bind_address(&server.bind, server.port)).await?;
+        let address = listener.local_addr()?;
+        match tls_config {
+            Some(config) => {
+                println!("Server running at https://{}", address);
+                run_server(tls::TlsListener::new(listener, config)?, app).await?;
+            }
+            None => {
+                println!("Server running at http://{}", address);
+                run_server(listener, app).await?;
+            }
+        }
+    }
 
-    // Start the server and listen on the correct port:
-    axum::serve(listener, app).await?;
+    // Every request has been answered: let the pool finish its work and close the database.
+    db.close().await;
+    tracing::info!("server stopped");
 
     Ok(())
 }
 
+// The address to listen on: --bind as host:port, or --bind as a bare host or IP address
+// with --port. IPv6 addresses may be given in brackets, with or without a port:
+fn bind_address(bind: &str, port: u16) -> String {
+    if bind.parse::<SocketAddr>().is_ok() {
+        return bind.to_string();
+    }
+    let host = bind.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) if host.contains(':') => bind.to_string(), // hostname:port
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+// A socket file left behind by an earlier run makes the bind fail, so remove it.
+// Anything at the path that is not a socket is left alone:
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+// Serve the app until shutdown_signal fires, then wait for the requests in flight:
+async fn run_server<L>(listener: L, app: axum::Router) -> std::io::Result<()>
+where
+    L: axum::serve::Listener,
+    L::Addr: std::fmt::Debug,
+{
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+}
+
+// Resolves on Ctrl-C or SIGTERM (what docker stop sends):
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate => (),
+    }
+    tracing::info!("shutting down, finishing requests in flight");
+}
+
 #[tokio::main]
 async fn main() {
-    serve().await.expect("No famous quote found");
+    let args = cli::Args::parse(); // Parse the cli arguments and flags.
+
+    let result = async {
+        // Layer the flags over the config file and the environment, then check the result:
+        let mut config = config::Config::load(args.config.as_deref())?;
+        let command = args.command.unwrap_or(cli::Command::Serve(args.serve));
+        cli::apply_flags(&mut config, args.db_uri, &command);
+        config.validate()?;
+
+        match command {
+            cli::Command::Serve(_) => serve(config).await,
+            command => cli::run(command, &config).await,
+        }
+    };
+    if let Err(e) = result.await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,265 @@
+/*
+Prometheus metrics, served as text at /metrics. They are collected in three places:
+    1) track_requests, a middleware next to the trace and CORS layers, counts and times
+       every request by method, route and status.
+    2) QueryLayer, a tracing layer, times every database query from the events sqlx
+       already emits for each statement.
+    3) ApiError::into_response counts auth failures by AuthError variant.
+The pool is read fresh on every scrape. The quote and tag counts scan their tables and
+/metrics is open to anyone, so they are recounted at most once every COUNT_INTERVAL.
+ */
+use crate::*;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing_subscriber::layer::Context;
+
+// How long the quote and tag counts are reused before they are counted again:
+const COUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Query latencies are much shorter than request latencies, so they get finer buckets:
+const DB_QUERY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    db_query_duration: HistogramVec,
+    db_pool_connections: IntGaugeVec,
+    db_pool_max_connections: IntGauge,
+    quotes: IntGauge,
+    tags: IntGauge,
+    auth_failures: IntCounterVec,
+    counted_at: Mutex<Option<Instant>>, // When quotes and tags were last counted.
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("quote_server".to_string()), None)
+            .expect("valid metric prefix");
+        fn register<M: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            metric: M,
+        ) -> M {
+            registry
+                .register(Box::new(metric.clone()))
+                .expect("metric names are unique");
+            metric
+        }
+
+        let http_requests = IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "HTTP requests answered, by method, route and status",
+            ),
+            &["method", "route", "status"],
+        );
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time to answer HTTP requests, by method and route",
+            ),
+            &["method", "route"],
+        );
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time taken by database queries, by statement kind",
+            )
+            .buckets(DB_QUERY_BUCKETS.to_vec()),
+            &["statement"],
+        );
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Open database connections, by state"),
+            &["state"],
+        );
+        let db_pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "Most database connections the pool will open",
+        );
+        let quotes = IntGauge::new(
+            "quotes",
+            "Quotes in the database, counted at most a minute ago",
+        );
+        let tags = IntGauge::new(
+            "tags",
+            "Distinct tags in the database, counted at most a minute ago",
+        );
+        let auth_failures = IntCounterVec::new(
+            Opts::new(
+                "auth_failures_total",
+                "Requests refused by authentication or authorization, by reason",
+            ),
+            &["reason"],
+        );
+
+        Self {
+            http_requests: register(&registry, http_requests.expect("valid metric")),
+            http_request_duration: register(
+                &registry,
+                http_request_duration.expect("valid metric"),
+            ),
+            db_query_duration: register(&registry, db_query_duration.expect("valid metric")),
+            db_pool_connections: register(&registry, db_pool_connections.expect("valid metric")),
+            db_pool_max_connections: register(
+                &registry,
+                db_pool_max_connections.expect("valid metric"),
+            ),
+            quotes: register(&registry, quotes.expect("valid metric")),
+            tags: register(&registry, tags.expect("valid metric")),
+            auth_failures: register(&registry, auth_failures.expect("valid metric")),
+            counted_at: Mutex::new(None),
+            registry,
+        }
+    }
+}
+
+// Middleware counting and timing every request. Requests are labelled with the route
+// pattern they matched, e.g. /api/v1/quote/{id}, so the number of series stays bounded.
+pub async fn track_requests(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> response::Response {
+    let method = request.method().clone();
+    let route = match request.extensions().get::<axum::extract::MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => "unmatched".to_string(),
+    };
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    METRICS
+        .http_request_duration
+        .with_label_values(&[method.as_str(), &route])
+        .observe(elapsed);
+    METRICS
+        .http_requests
+        .with_label_values(&[method.as_str(), &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+// Count a request refused with this auth error, labelled with its problem type, such as
+// invalid-token or expired-token. Database errors are server failures, not refusals, so
+// they are left out:
+pub fn auth_failure(error: &authjwt::AuthError) {
+    if matches!(error, authjwt::AuthError::Database) {
+        return;
+    }
+    let (_, reason, _) = error.kind();
+    METRICS.auth_failures.with_label_values(&[reason]).inc();
+}
+
+// Every metric in the Prometheus text format:
+pub async fn render(db: &SqlitePool) -> Result<String, sqlx::Error> {
+    let size = i64::from(db.size());
+    let idle = i64::try_from(db.num_idle()).unwrap_or(i64::MAX);
+    METRICS
+        .db_pool_connections
+        .with_label_values(&["idle"])
+        .set(idle);
+    METRICS
+        .db_pool_connections
+        .with_label_values(&["in_use"])
+        .set(size - idle);
+    METRICS
+        .db_pool_max_connections
+        .set(i64::from(db.options().get_max_connections()));
+
+    // Claim the recount before counting, so scrapes arriving together count only once.
+    let recount = {
+        let mut counted_at = METRICS
+            .counted_at
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match *counted_at {
+            Some(at) if at.elapsed() < COUNT_INTERVAL => false,
+            _ => {
+                *counted_at = Some(Instant::now());
+                true
+            }
+        }
+    };
+    if recount {
+        if let Err(e) = count_quotes_and_tags(db).await {
+            // Let the next scrape try again.
+            *METRICS
+                .counted_at
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = None;
+            return Err(e);
+        }
+    }
+
+    // Encoding only fails on malformed metrics, which Metrics::new rules out.
+    let mut text = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut text) {
+        log::error!("metrics encoding failed: {}", e);
+    }
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+// Count the quotes and distinct tags into their gauges:
+async fn count_quotes_and_tags(db: &SqlitePool) -> Result<(), sqlx::Error> {
+    let quotes: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM quotes")
+        .fetch_one(db)
+        .await?;
+    let tags: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT tag) FROM tags")
+        .fetch_one(db)
+        .await?;
+    METRICS.quotes.set(quotes);
+    METRICS.tags.set(tags);
+    Ok(())
+}
+
+// Tracing layer timing database queries. sqlx emits an event under the sqlx::query target
+// for each statement it runs, with its first words as "summary" and its run time as
+// "elapsed_secs". Installed with a filter that lets only those events through.
+pub struct QueryLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for QueryLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let mut query = QueryEvent::default();
+        event.record(&mut query);
+        if let (Some(statement), Some(elapsed)) = (query.statement, query.elapsed_secs) {
+            METRICS
+                .db_query_duration
+                .with_label_values(&[statement.as_str()])
+                .observe(elapsed);
+        }
+    }
+}
+
+// The fields of a sqlx::query event that QueryLayer needs:
+#[derive(Default)]
+struct QueryEvent {
+    statement: Option<String>, // The SQL keyword the statement starts with, lower case.
+    elapsed_secs: Option<f64>,
+}
+
+impl tracing::field::Visit for QueryEvent {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "summary" {
+            let keyword = value.split_whitespace().next().unwrap_or("other");
+            self.statement = Some(keyword.to_lowercase());
+        }
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {}
+}
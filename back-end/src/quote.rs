@@ -1,39 +1,519 @@
 /// This file defines the JsonQuote and the Quote structs. The Json Quote allows movement of quote
 /// data over the api. The Quote struct is used to create quotes and grab them from the database.
 /// This file has the functions that get the tags and Quotes from the database.
-
 use crate::*;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
 use std::ops::Deref;
 use std::path::Path;
 
 use crate::QuoteError;
 
 use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite};
 
 // Struct that sends Json quotes over the api:
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct JsonQuote {
-    pub id: String,            // Unique id "1", "2", etc.
+    #[serde(default)]
+    pub id: String, // Unique id "1", "2", etc. The server assigns a ULID if left out.
     pub quote: String,         // The famous quote.
     pub author: String,        // Author of the quote.
     pub tags: HashSet<String>, // Set of tags (themes) of the quote: ['love', 'life']
 }
 
+// Struct for partial quote updates over the api. Missing fields are left unchanged:
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct JsonQuotePatch {
+    pub quote: Option<String>,         // Replacement quote text.
+    pub author: Option<String>,        // Replacement author.
+    pub tags: Option<HashSet<String>>, // Replacement set of tags.
+}
+
 // The famous quote struct. Contains and id, a quote, and its author:
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Quote {
     pub id: String,
     pub quote: String,
     pub author: String,
 }
 
-// Read quotes from the quotes.json and parse them into JsonQuote objects:
-pub fn read_quotes<P: AsRef<Path>>(quotes_path: P) -> Result<Vec<JsonQuote>, QuoteError> {
+// Sort orders available when listing quotes:
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteSort {
+    #[default]
+    Id, // By quote id.
+    Author, // By author name, case-insensitive.
+    Length, // By the length of the quote text, shortest first.
+}
+
+impl QuoteSort {
+    // The SQL ordering for this sort. Ties are broken by id so pages are stable:
+    fn order_by(self) -> &'static str {
+        match self {
+            QuoteSort::Id => "id",
+            QuoteSort::Author => "author collate nocase, id",
+            QuoteSort::Length => "length(quote), id",
+        }
+    }
+
+    // Append a condition, starting with "and", for the quotes that come after this one in
+    // this sort order:
+    fn push_after<'a>(self, query: &mut QueryBuilder<'a, Sqlite>, last: &'a Quote) {
+        match self {
+            QuoteSort::Id => query.push(" and id > "),
+            QuoteSort::Author => {
+                query.push(" and (author collate nocase, id) > (");
+                query.push_bind(&last.author);
+                query.push(", ")
+            }
+            QuoteSort::Length => {
+                query.push(" and (length(quote), id) > (length(");
+                query.push_bind(&last.quote);
+                query.push("), ")
+            }
+        };
+        query.push_bind(&last.id);
+        if !matches!(self, QuoteSort::Id) {
+            query.push(")");
+        }
+    }
+}
+
+// How the requested tags of a TagFilter must match a quote's tags:
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMatch {
+    #[default]
+    Any, // The quote carries at least one of the tags.
+    All, // The quote carries every one of the tags.
+}
+
+// Selects quotes by their tags. It is shared by every query that picks quotes by tag:
+// tagged quotes, listing and search. An empty filter matches every quote.
+#[derive(Debug, Clone, Default)]
+pub struct TagFilter {
+    pub tags: Vec<String>, // Requested tags, matched according to mode.
+    pub mode: TagMatch,
+    pub exclude: Vec<String>, // Quotes carrying any of these are left out.
+}
+
+impl TagFilter {
+    // Build a filter from comma-separated tag lists, as given in query parameters. Tags
+    // are normalized like stored tags, and blanks and repeats are dropped:
+    pub fn new(tags: Option<&str>, mode: Option<TagMatch>, exclude: Option<&str>) -> Self {
+        fn tag_list(tags: Option<&str>) -> Vec<String> {
+            let mut seen = HashSet::new();
+            tags.unwrap_or_default()
+                .split(',')
+                .map(normalize_tag)
+                .filter(|tag| !tag.is_empty() && seen.insert(tag.clone()))
+                .collect()
+        }
+
+        Self {
+            tags: tag_list(tags),
+            mode: mode.unwrap_or_default(),
+            exclude: tag_list(exclude),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.exclude.is_empty()
+    }
+
+    // Append this filter's conditions, each starting with "and", to a query whose where
+    // clause is on the quotes table. Each tag list is bound as a single JSON array and
+    // unpacked with json_each, so the statement is the same however many tags are given.
+    // The subqueries do not depend on the quote, so each runs once, on the tags index.
+    pub fn push_conditions<'a>(&'a self, query: &mut QueryBuilder<'a, Sqlite>) {
+        fn json_list(tags: &[String]) -> String {
+            serde_json::Value::from(tags).to_string()
+        }
+
+        if !self.tags.is_empty() {
+            query.push(" and quotes.id in (select quote_id from tags where tag in (select value from json_each(");
+            query.push_bind(json_list(&self.tags));
+            query.push("))");
+            if self.mode == TagMatch::All {
+                // The tags are distinct, so a quote has all of them when it has that many.
+                query.push(" group by quote_id having count(distinct tag) = ");
+                query.push_bind(self.tags.len() as i64);
+            }
+            query.push(")");
+        }
+        if !self.exclude.is_empty() {
+            query.push(" and quotes.id not in (select quote_id from tags where tag in (select value from json_each(");
+            query.push_bind(json_list(&self.exclude));
+            query.push(")))");
+        }
+    }
+}
+
+// Describes the filter for messages, e.g. "tagged with love and life, but not death":
+impl std::fmt::Display for TagFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let joiner = match self.mode {
+            TagMatch::Any => " or ",
+            TagMatch::All => " and ",
+        };
+        match (self.tags.is_empty(), self.exclude.is_empty()) {
+            (_, true) => write!(f, "tagged with {}", self.tags.join(joiner)),
+            (true, false) => write!(f, "without the tags {}", self.exclude.join(", ")),
+            (false, false) => write!(
+                f,
+                "tagged with {}, but not {}",
+                self.tags.join(joiner),
+                self.exclude.join(" or ")
+            ),
+        }
+    }
+}
+
+// Filters available when listing quotes. Unset fields match everything:
+#[derive(Debug, Default)]
+pub struct QuoteFilter {
+    pub author: Option<String>, // Exact author name, case-insensitive.
+    pub tags: TagFilter,
+}
+
+impl QuoteFilter {
+    // Append the where clause for this filter to a query on the quotes table:
+    fn push_where<'a>(&'a self, query: &mut QueryBuilder<'a, Sqlite>) {
+        query.push(" where 1 = 1");
+        if let Some(author) = &self.author {
+            query.push(" and author = ");
+            query.push_bind(author);
+            query.push(" collate nocase");
+        }
+        self.tags.push_conditions(query);
+    }
+}
+
+// A full-text search result: the matching quote plus a snippet of its text with the
+// matched words wrapped in <mark> tags:
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub quote: JsonQuote,
+    pub snippet: String,
+}
+
+// Row shape returned by the full-text search query:
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    quote: QuoteRow,
+    snippet: String,
+}
+
+// Longest id, quote text, author and tag accepted, in characters. These follow the
+// varchar(200) columns in the migrations:
+pub const MAX_ID_LEN: usize = 200;
+pub const MAX_QUOTE_LEN: usize = 200;
+pub const MAX_AUTHOR_LEN: usize = 200;
+pub const MAX_TAG_LEN: usize = 50;
+// Most tags one quote may carry:
+pub const MAX_TAGS: usize = 20;
+
+// Normalize a tag the same way everywhere: trimmed, lowercase, with inner runs of
+// whitespace collapsed to one space ("  I  Love You " becomes "i love you"):
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Check that a normalized tag is not blank, not too long, and only has letters, digits,
+// spaces and dashes:
+fn check_tag(tag: &str) -> Option<String> {
+    if tag.is_empty() {
+        Some("tags must not be blank".to_string())
+    } else if tag.chars().count() > MAX_TAG_LEN {
+        Some(format!(
+            "tag \"{}\" is longer than {} characters",
+            tag, MAX_TAG_LEN
+        ))
+    } else if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == ' ' || c == '-')
+    {
+        Some(format!(
+            "tag \"{}\" may only contain letters, digits, spaces and dashes",
+            tag
+        ))
+    } else {
+        None
+    }
+}
+
+// Check that a trimmed text field is not blank and not longer than max characters:
+fn check_text(field: &str, text: &str, max: usize, errors: &mut Vec<FieldError>) {
+    let message = if text.is_empty() {
+        "must not be blank".to_string()
+    } else if text.chars().count() > max {
+        format!("must be at most {} characters", max)
+    } else {
+        return;
+    };
+    errors.push(FieldError {
+        field: field.to_string(),
+        message,
+    });
+}
+
+// File formats quotes can be read from and written to:
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteFormat {
+    #[default]
+    Json, // A JSON array of quotes, like assets/static/quotes.json.
+    Ndjson,  // One JSON quote per line.
+    Csv,     // id,quote,author,tags columns with a header row; tags separated by ';'.
+    Yaml,    // A YAML sequence of quotes.
+    Fortune, // fortune(6) text: entries separated by '%' lines, author on a "-- " line.
+}
+
+// Separator between tags in the CSV tags column:
+const CSV_TAG_SEPARATOR: char = ';';
+
+// One row of a CSV quote file:
+#[derive(Serialize, Deserialize)]
+struct CsvQuote {
+    #[serde(default)]
+    id: String,
+    quote: String,
+    author: String,
+    #[serde(default)]
+    tags: String,
+}
+
+impl QuoteFormat {
+    // Guess the format from a file name's extension:
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(QuoteFormat::Json),
+            "ndjson" | "jsonl" => Some(QuoteFormat::Ndjson),
+            "csv" => Some(QuoteFormat::Csv),
+            "yaml" | "yml" => Some(QuoteFormat::Yaml),
+            "fortune" | "fortunes" => Some(QuoteFormat::Fortune),
+            _ => None,
+        }
+    }
+
+    // Guess the format from a Content-Type header value:
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let essence = mime.split(';').next()?.trim().to_lowercase();
+        match essence.as_str() {
+            "application/json" => Some(QuoteFormat::Json),
+            "application/x-ndjson" | "application/jsonl" => Some(QuoteFormat::Ndjson),
+            "text/csv" => Some(QuoteFormat::Csv),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(QuoteFormat::Yaml),
+            "text/plain" => Some(QuoteFormat::Fortune),
+            _ => None,
+        }
+    }
+
+    // The Content-Type used when sending quotes in this format:
+    pub fn mime(self) -> &'static str {
+        match self {
+            QuoteFormat::Json => "application/json",
+            QuoteFormat::Ndjson => "application/x-ndjson",
+            QuoteFormat::Csv => "text/csv; charset=utf-8",
+            QuoteFormat::Yaml => "application/yaml",
+            QuoteFormat::Fortune => "text/plain; charset=utf-8",
+        }
+    }
+
+    // The usual file extension for this format:
+    pub fn extension(self) -> &'static str {
+        match self {
+            QuoteFormat::Json => "json",
+            QuoteFormat::Ndjson => "ndjson",
+            QuoteFormat::Csv => "csv",
+            QuoteFormat::Yaml => "yaml",
+            QuoteFormat::Fortune => "fortune",
+        }
+    }
+
+    // Parse all the quotes in a reader. Quotes are not validated here, see JsonQuote::validate.
+    pub fn read<R: std::io::Read>(self, mut reader: R) -> Result<Vec<JsonQuote>, QuoteError> {
+        match self {
+            QuoteFormat::Json => Ok(serde_json::from_reader(reader)?),
+            QuoteFormat::Ndjson => {
+                let mut quotes = Vec::new();
+                for line in std::io::BufReader::new(reader).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        quotes.push(serde_json::from_str(&line)?);
+                    }
+                }
+                Ok(quotes)
+            }
+            QuoteFormat::Csv => {
+                let mut quotes = Vec::new();
+                for row in csv::Reader::from_reader(reader).deserialize() {
+                    let row: CsvQuote = row?;
+                    let tags = row
+                        .tags
+                        .split(CSV_TAG_SEPARATOR)
+                        .filter(|tag| !tag.trim().is_empty())
+                        .map(str::to_string)
+                        .collect();
+                    quotes.push(JsonQuote {
+                        id: row.id,
+                        quote: row.quote,
+                        author: row.author,
+                        tags,
+                    });
+                }
+                Ok(quotes)
+            }
+            QuoteFormat::Yaml => Ok(serde_yaml::from_reader(reader)?),
+            QuoteFormat::Fortune => {
+                let mut text = String::new();
+                reader.read_to_string(&mut text)?;
+                Ok(read_fortunes(&text))
+            }
+        }
+    }
+}
+
+// Split fortune(6) text into quotes. Entries are separated by lines holding just '%'. A last
+// line starting with "--" (or "―") is the author; entries without one are anonymous. Fortune
+// files have no ids or tags, so the quotes get neither.
+fn read_fortunes(text: &str) -> Vec<JsonQuote> {
+    let mut quotes = Vec::new();
+    let mut entry: Vec<&str> = Vec::new();
+    for line in text.lines().chain(std::iter::once("%")) {
+        if line.trim_end() != "%" {
+            entry.push(line);
+            continue;
+        }
+
+        while entry.last().is_some_and(|line| line.trim().is_empty()) {
+            entry.pop();
+        }
+        let author = entry
+            .last()
+            .map(|line| line.trim())
+            .and_then(|line| line.strip_prefix("--").or_else(|| line.strip_prefix('―')))
+            .map(|author| author.trim().to_string());
+        if author.is_some() {
+            entry.pop();
+        }
+
+        let quote = entry.join("\n");
+        if !quote.trim().is_empty() {
+            quotes.push(JsonQuote {
+                id: String::new(),
+                quote,
+                author: author.unwrap_or_else(|| "Anonymous".to_string()),
+                tags: HashSet::new(),
+            });
+        }
+        entry.clear();
+    }
+    quotes
+}
+
+// Writes quotes one at a time in a given format. Whatever goes before the first quote or
+// after the last one (JSON brackets, the CSV header) is handled here, so callers can stream
+// quotes out without holding them all:
+pub struct QuoteWriter<W: std::io::Write> {
+    format: QuoteFormat,
+    out: W,
+    written: usize, // Number of quotes written so far.
+}
+
+impl<W: std::io::Write> QuoteWriter<W> {
+    pub fn new(format: QuoteFormat, out: W) -> Self {
+        Self {
+            format,
+            out,
+            written: 0,
+        }
+    }
+
+    // The output written to so far, e.g. to drain a buffer between quotes:
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    // Write one quote:
+    pub fn write(&mut self, json_quote: &JsonQuote) -> Result<(), QuoteError> {
+        let first = self.written == 0;
+        match self.format {
+            QuoteFormat::Json => {
+                self.out.write_all(if first { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut self.out, json_quote)?;
+            }
+            QuoteFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, json_quote)?;
+                self.out.write_all(b"\n")?;
+            }
+            QuoteFormat::Csv => {
+                let mut tags: Vec<&str> = json_quote.tags.iter().map(String::deref).collect();
+                tags.sort_unstable();
+                let row = CsvQuote {
+                    id: json_quote.id.clone(),
+                    quote: json_quote.quote.clone(),
+                    author: json_quote.author.clone(),
+                    tags: tags.join(&CSV_TAG_SEPARATOR.to_string()),
+                };
+                let mut csv = csv::WriterBuilder::new()
+                    .has_headers(first)
+                    .from_writer(&mut self.out);
+                csv.serialize(row)?;
+                csv.flush()?;
+            }
+            QuoteFormat::Yaml => serde_yaml::to_writer(&mut self.out, &[json_quote])?,
+            QuoteFormat::Fortune => {
+                if !first {
+                    self.out.write_all(b"%\n")?;
+                }
+                writeln!(
+                    self.out,
+                    "{}\n\t\t-- {}",
+                    json_quote.quote, json_quote.author
+                )?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    // Write whatever has to follow the last quote and hand back the output:
+    pub fn finish(mut self) -> Result<W, QuoteError> {
+        match self.format {
+            QuoteFormat::Json if self.written == 0 => self.out.write_all(b"[]\n")?,
+            QuoteFormat::Json => self.out.write_all(b"\n]\n")?,
+            QuoteFormat::Csv if self.written == 0 => {
+                self.out.write_all(b"id,quote,author,tags\n")?
+            }
+            QuoteFormat::Yaml if self.written == 0 => self.out.write_all(b"[]\n")?,
+            _ => (),
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Read quotes from a file in the given format, or the one its extension suggests. Files
+// with an unknown extension are read as a JSON array, like assets/static/quotes.json:
+pub fn read_quotes<P: AsRef<Path>>(
+    quotes_path: P,
+    format: Option<QuoteFormat>,
+) -> Result<Vec<JsonQuote>, QuoteError> {
+    let format = format
+        .or_else(|| QuoteFormat::from_path(&quotes_path))
+        .unwrap_or_default();
     let f = std::fs::File::open(quotes_path.as_ref())?;
-    let quotes = serde_json::from_reader(f)?;
-    Ok(quotes)
+    format.read(std::io::BufReader::new(f))
 }
 
 // Implementation methods for the json quote struct:
@@ -49,6 +529,89 @@ impl JsonQuote {
         }
     }
 
+    // Give the quote a new unique id (a ULID) if it came without one:
+    pub fn assign_id(&mut self) {
+        if self.id.trim().is_empty() {
+            self.id = ulid::Ulid::new().to_string();
+        }
+    }
+
+    // Normalize the quote in place and check that it can be stored. The id, quote text and
+    // author are trimmed and the tags normalized with normalize_tag. Every invalid field is
+    // reported, not just the first. An empty id is allowed, see assign_id.
+    pub fn validate(&mut self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        self.id = self.id.trim().to_string();
+        if self.id.chars().count() > MAX_ID_LEN {
+            errors.push(FieldError {
+                field: "id".to_string(),
+                message: format!("must be at most {} characters", MAX_ID_LEN),
+            });
+        }
+
+        self.quote = self.quote.trim().to_string();
+        check_text("quote", &self.quote, MAX_QUOTE_LEN, &mut errors);
+
+        self.author = self.author.trim().to_string();
+        check_text("author", &self.author, MAX_AUTHOR_LEN, &mut errors);
+
+        // Tags that only differ in case or spacing are duplicates once normalized:
+        let mut tags = HashSet::with_capacity(self.tags.len());
+        for tag in &self.tags {
+            let tag = normalize_tag(tag);
+            let message = check_tag(&tag).or_else(|| {
+                tags.contains(&tag)
+                    .then(|| format!("tag \"{}\" is given more than once", tag))
+            });
+            match message {
+                Some(message) => errors.push(FieldError {
+                    field: "tags".to_string(),
+                    message,
+                }),
+                None => {
+                    tags.insert(tag);
+                }
+            }
+        }
+        if tags.len() > MAX_TAGS {
+            errors.push(FieldError {
+                field: "tags".to_string(),
+                message: format!("at most {} tags are allowed", MAX_TAGS),
+            });
+        }
+        self.tags = tags;
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Describe how other differs from this quote, one line per changed field. Empty if
+    // they hold the same text, author and tags:
+    pub fn changes(&self, other: &JsonQuote) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.quote != other.quote {
+            changes.push(format!("quote: {:?} -> {:?}", self.quote, other.quote));
+        }
+        if self.author != other.author {
+            changes.push(format!("author: {:?} -> {:?}", self.author, other.author));
+        }
+        if self.tags != other.tags {
+            let mut added: Vec<&String> = other.tags.difference(&self.tags).collect();
+            let mut removed: Vec<&String> = self.tags.difference(&other.tags).collect();
+            added.sort_unstable();
+            removed.sort_unstable();
+            let added = added.iter().map(|tag| format!("+{}", tag));
+            let removed = removed.iter().map(|tag| format!("-{}", tag));
+            let tags: Vec<String> = added.chain(removed).collect();
+            changes.push(format!("tags: {}", tags.join(" ")));
+        }
+        changes
+    }
+
     // Creates a Quote object from the JsonQuote instance and returns it plus the tags.
     pub fn to_quote(&self) -> (Quote, impl Iterator<Item = &str>) {
         let quote = Quote {
@@ -61,6 +624,22 @@ impl JsonQuote {
     }
 }
 
+// Implementation methods for the json quote patch struct:
+impl JsonQuotePatch {
+    // Apply the patch to an existing quote, replacing only the fields that were given:
+    pub fn apply(self, json_quote: &mut JsonQuote) {
+        if let Some(quote) = self.quote {
+            json_quote.quote = quote;
+        }
+        if let Some(author) = self.author {
+            json_quote.author = author;
+        }
+        if let Some(tags) = self.tags {
+            json_quote.tags = tags;
+        }
+    }
+}
+
 // Converts a JsonQuote object into an http response type:
 impl axum::response::IntoResponse for &JsonQuote {
     fn into_response(self) -> axum::response::Response {
@@ -85,62 +664,908 @@ pub async fn get(db: &SqlitePool, quote_id: &str) -> Result<(Quote, Vec<String>)
     Ok((quote, tags)) // Return the tuple.
 }
 
-// Given the database pool and the tags, get a quote from the db that matches that tag:
-pub async fn get_tagged<'a, I>(db: &SqlitePool, tags: I) -> Result<Option<String>, sqlx::Error>
-where
-    I: Iterator<Item = &'a str>,
-{
-    let mut qtx = db.begin().await?; // Begin a transaction with the databse.
+// The columns of a QuoteRow, for a query on the quotes table. The tags of each quote are
+// gathered into a JSON array by a subquery on the tags_quote_id_tag index, so quotes and
+// their tags come back together from a single statement:
+const QUOTE_COLUMNS: &str = "quotes.id, quotes.quote, quotes.author,
+    (select json_group_array(tag) from tags where tags.quote_id = quotes.id) as tags";
 
-    // Drop the qtags table (if it exists), then create a temporary table called qtags with one column: tag.
-    sqlx::query("drop table if exists qtags;")
-        .execute(&mut *qtx)
-        .await?;
-    sqlx::query("create temporary table qtags (tag varchar(200));")
-        .execute(&mut *qtx)
-        .await?;
+// Row shape of QUOTE_COLUMNS, with the tags gathered into a JSON array:
+#[derive(sqlx::FromRow)]
+struct QuoteRow {
+    id: String,
+    quote: String,
+    author: String,
+    tags: sqlx::types::Json<Vec<String>>,
+}
+
+impl From<QuoteRow> for JsonQuote {
+    fn from(row: QuoteRow) -> Self {
+        let quote = Quote {
+            id: row.id,
+            quote: row.quote,
+            author: row.author,
+        };
+        JsonQuote::new(quote, row.tags.0)
+    }
+}
+
+// Quotes read by each query of a stream:
+const STREAM_PAGE_SIZE: u32 = 500;
+
+// The query for the quotes matching a filter, in sort order, skipping offset quotes and
+// returning at most limit of them, or all of them with no limit. Given the last quote of
+// a previous page, only the quotes after it are returned:
+fn select_quotes<'a>(
+    filter: &'a QuoteFilter,
+    sort: QuoteSort,
+    after: Option<&'a Quote>,
+    limit: Option<u32>,
+    offset: u32,
+) -> QueryBuilder<'a, Sqlite> {
+    let mut query = QueryBuilder::new("select ");
+    query.push(QUOTE_COLUMNS);
+    query.push(" from quotes");
+    filter.push_where(&mut query);
+    if let Some(last) = after {
+        sort.push_after(&mut query, last);
+    }
+    query.push(" order by ");
+    query.push(sort.order_by());
+    // SQLite takes a negative limit as no limit at all.
+    query.push(" limit ");
+    query.push_bind(limit.map_or(-1, i64::from));
+    query.push(" offset ");
+    query.push_bind(i64::from(offset));
+    query
+}
+
+// Given the database pool, a filter and a sort order, get one page of quotes with their
+// tags, along with the total number of quotes matching the filter:
+pub async fn list(
+    db: &SqlitePool,
+    filter: &QuoteFilter,
+    sort: QuoteSort,
+    limit: u32,
+    offset: u32,
+) -> Result<(Vec<JsonQuote>, i64), sqlx::Error> {
+    let mut count_query = QueryBuilder::new("select count(*) from quotes");
+    filter.push_where(&mut count_query);
+    let total: i64 = count_query.build_query_scalar().fetch_one(db).await?;
+
+    let mut page_query = select_quotes(filter, sort, None, Some(limit), offset);
+    let rows: Vec<QuoteRow> = page_query.build_query_as().fetch_all(db).await?;
+    let quotes = rows.into_iter().map(JsonQuote::from).collect();
+
+    Ok((quotes, total))
+}
+
+// Given the database pool, a filter and a sort order, stream the matching quotes with their
+// tags, like list does for a page. The quotes are read a page at a time on a task of its
+// own, as the stream is polled, so they are never all in memory. Each page starts after the
+// last quote of the one before, and a connection is only held while a page is read: a slow
+// reader never ties one up. The task stops once the stream is dropped:
+pub fn stream(
+    db: SqlitePool,
+    filter: QuoteFilter,
+    sort: QuoteSort,
+    limit: Option<u32>,
+    offset: u32,
+) -> impl tokio_stream::Stream<Item = Result<JsonQuote, sqlx::Error>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        let mut last: Option<Quote> = None;
+        let mut remaining = limit;
+        loop {
+            let page_size = remaining.map_or(STREAM_PAGE_SIZE, |left| left.min(STREAM_PAGE_SIZE));
+            if page_size == 0 {
+                return;
+            }
+            // The offset only applies before the first page.
+            let skip = if last.is_none() { offset } else { 0 };
+            let page: Result<Vec<QuoteRow>, sqlx::Error> =
+                select_quotes(&filter, sort, last.as_ref(), Some(page_size), skip)
+                    .build_query_as()
+                    .fetch_all(&db)
+                    .await;
+            let rows = match page {
+                Ok(rows) => rows,
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            };
+
+            let full = rows.len() == page_size as usize;
+            remaining = remaining.map(|left| left - rows.len() as u32);
+            for row in rows {
+                last = Some(Quote {
+                    id: row.id.clone(),
+                    quote: row.quote.clone(),
+                    author: row.author.clone(),
+                });
+                if sender.send(Ok(JsonQuote::from(row))).await.is_err() {
+                    return;
+                }
+            }
+            if !full {
+                return;
+            }
+        }
+    });
+    tokio_stream::wrappers::ReceiverStream::new(receiver)
+}
+
+// Translate user search text into an FTS5 match expression. Every term is quoted so
+// user input can never be an FTS5 syntax error. Supported forms:
+//     words            all words must appear (in any order)
+//     "some phrase"    the words must appear together in that order
+//     wor*  "a phr"*   prefix match on the last word
+//     author:twain     restrict the following word or phrase to the author
+// Returns None if there is nothing to search for.
+pub fn search_expression(text: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        // An author: scope applies to the word or phrase right after it.
+        let mut column = None;
+        if let Some(prefix) = rest.get(..7) {
+            if prefix.eq_ignore_ascii_case("author:") {
+                column = Some("author");
+                rest = rest[7..].trim_start();
+            }
+        }
+
+        let (term, tail) = if let Some(phrase) = rest.strip_prefix('"') {
+            let end = phrase.find('"').unwrap_or(phrase.len());
+            let tail = phrase[end..].strip_prefix('"').unwrap_or(&phrase[end..]);
+            (&phrase[..end], tail)
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+
+        // A trailing * (inside or just after the quotes) makes it a prefix match.
+        let (term, tail, prefix) = match (term.strip_suffix('*'), tail.strip_prefix('*')) {
+            (Some(term), _) => (term, tail, true),
+            (None, Some(tail)) => (term, tail, true),
+            (None, None) => (term, tail, false),
+        };
+        rest = tail.trim_start();
+
+        let term = term.trim().replace('"', "\"\"");
+        if term.is_empty() {
+            continue;
+        }
+
+        let mut expression = format!("\"{}\"", term);
+        if prefix {
+            expression.push_str(" *");
+        }
+        if let Some(column) = column {
+            expression = format!("{} : {}", column, expression);
+        }
+        terms.push(expression);
+    }
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+// Given the database pool and an FTS5 match expression, get the best matching quotes
+// in rank order with highlighted snippets:
+pub async fn search(
+    db: &SqlitePool,
+    expression: &str,
+    tags: &TagFilter,
+    limit: u32,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let mut query = QueryBuilder::new("select ");
+    query.push(QUOTE_COLUMNS);
+    query.push(
+        ",
+                snippet(quotes_fts, 1, '<mark>', '</mark>', '…', 32) as snippet
+            from quotes_fts
+            join quotes
+            on quotes.id = quotes_fts.quote_id
+            where quotes_fts match ",
+    );
+    query.push_bind(expression);
+    tags.push_conditions(&mut query);
+    query.push(" order by quotes_fts.rank limit ");
+    query.push_bind(i64::from(limit));
+    let rows: Vec<SearchRow> = query.build_query_as().fetch_all(db).await?;
+
+    let hits = rows
+        .into_iter()
+        .map(|row| SearchHit {
+            quote: row.quote.into(),
+            snippet: row.snippet,
+        })
+        .collect();
+
+    Ok(hits)
+}
+
+// Given an open transaction and a quote, insert the quote row and its tags. Fails with a
+// unique violation if a quote with that id already exists:
+pub async fn insert(
+    tx: &mut sqlx::SqliteConnection,
+    json_quote: &JsonQuote,
+) -> Result<(), sqlx::Error> {
+    let (quote, tags) = json_quote.to_quote();
+
+    sqlx::query!(
+        "insert into quotes (id, quote, author) values ($1, $2, $3);",
+        quote.id,
+        quote.quote,
+        quote.author,
+    )
+    .execute(&mut *tx)
+    .await?;
 
-    // For each tag in the tags list (given as a paramter to this method), insert that tag into the temporary table:
     for tag in tags {
-        sqlx::query("insert into qtags values ($1);")
-            .bind(tag)
-            .execute(&mut *qtx)
-            .await?;
+        sqlx::query!(
+            "insert into tags (quote_id, tag) values ($1, $2);",
+            quote.id,
+            tag
+        )
+        .execute(&mut *tx)
+        .await?;
     }
 
-    // Join the temporary qtags table with the persistant tags table on the tag column.
-    // Select only the unique tags from that join, and select the top one at random:
-    let quote_ids = sqlx::query(
-        "
-            select distinct quote_id 
-            from tags 
-            join qtags 
-            on tags.tag = qtags.tag 
-            order by random() 
-            limit 1;",
+    Ok(())
+}
+
+// Given an open transaction and a quote, replace the quote row with that id and
+// reconcile its tags. Returns false if there is no quote with that id:
+pub async fn update(
+    tx: &mut sqlx::SqliteConnection,
+    json_quote: &JsonQuote,
+) -> Result<bool, sqlx::Error> {
+    let (quote, tags) = json_quote.to_quote();
+
+    let result = sqlx::query!(
+        "update quotes set quote = $1, author = $2 where id = $3;",
+        quote.quote,
+        quote.author,
+        quote.id,
     )
-    .fetch_all(&mut *qtx)
+    .execute(&mut *tx)
     .await?;
 
-    // Get the quote ids generated from the previous query:
-    let nquote_ids = quote_ids.len();
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
 
-    // If there is one quote id, return it as the result.
-    let result = if nquote_ids == 1 {
-        Some(quote_ids[0].get(0))
-    } else {
-        None
-    };
+    // Replace the tags wholesale so removed tags disappear and new ones are added:
+    sqlx::query!("delete from tags where quote_id = $1;", quote.id)
+        .execute(&mut *tx)
+        .await?;
+
+    for tag in tags {
+        sqlx::query!(
+            "insert into tags (quote_id, tag) values ($1, $2);",
+            quote.id,
+            tag
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
 
-    qtx.commit().await?; // End and commit the transaction that started at the top of this function.
+    Ok(true)
+}
 
-    // Return the quote_id that had a matching theme (tag).
-    Ok(result)
+// Given the database pool and a tag filter, get the id of a random quote that matches it:
+pub async fn get_tagged(
+    db: &SqlitePool,
+    filter: &TagFilter,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut query = QueryBuilder::new("select id from quotes where 1 = 1");
+    filter.push_conditions(&mut query);
+    query.push(" order by random() limit 1");
+    query.build_query_scalar().fetch_optional(db).await
 }
 
-// Query the database and get a random quote id:
-pub async fn get_random(db: &SqlitePool) -> Result<String, sqlx::Error> {
-    sqlx::query_scalar!("select id from quotes order by random() limit 1;")
+// Random rowids get_random tries before it falls back to a random offset:
+const RANDOM_ROWID_TRIES: usize = 16;
+
+// Query the database and get a random quote id, each quote equally likely. Sorting the whole
+// table by random() costs a full scan per pick, so instead we try random rowids between the
+// lowest and highest in use and take the first that is still a quote. Every quote's rowid is
+// as likely to come up as any other, so the pick stays uniform whatever gaps deletes have left.
+// Only if the rowids are too sparse for any try to hit do we count the quotes and skip a random
+// number of them. Fails with RowNotFound if there are no quotes:
+pub async fn get_random(db: &SqlitePool, rng: &mut fastrand::Rng) -> Result<String, sqlx::Error> {
+    let Some((low, high)) = rowid_range(db).await? else {
+        return Err(sqlx::Error::RowNotFound);
+    };
+
+    for _ in 0..RANDOM_ROWID_TRIES {
+        if let Some(id) = id_at_rowid(db, rng.i64(low..=high)).await? {
+            return Ok(id);
+        }
+    }
+
+    let count: i64 = sqlx::query_scalar("select count(*) from quotes;")
+        .fetch_one(db)
+        .await?;
+    sqlx::query_scalar("select id from quotes limit 1 offset $1;")
+        .bind(rng.i64(0..count.max(1)))
         .fetch_one(db)
         .await
 }
+
+// The lowest and highest rowid in the quotes table, or None if it is empty:
+async fn rowid_range(db: &SqlitePool) -> Result<Option<(i64, i64)>, sqlx::Error> {
+    // Separate subqueries, so that SQLite answers each from the end of the rowid b-tree.
+    let (low, high): (Option<i64>, Option<i64>) =
+        sqlx::query_as("select (select min(rowid) from quotes), (select max(rowid) from quotes);")
+            .fetch_one(db)
+            .await?;
+    Ok(low.zip(high))
+}
+
+// The id of the quote with this rowid, if it is still there:
+async fn id_at_rowid(db: &SqlitePool, rowid: i64) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("select id from quotes where rowid = $1;")
+        .bind(rowid)
+        .fetch_optional(db)
+        .await
+}
+
+// Rounds of rowid sampling get_random_quotes makes before it falls back to shuffling ids:
+const RANDOM_ROWID_ROUNDS: usize = 4;
+
+// Given the database pool, a tag filter, a count and an optional seed, get up to count
+// distinct quotes with their tags, in random order: all the matching quotes if there are no
+// more than count of them. Filtered and seeded picks shuffle the ids of the matching quotes,
+// the same quotes get_tagged picks from, so with a seed the picks depend only on the seed
+// and those ids. Rowids are renumbered by imports without any quote changing, so only
+// unseeded, unfiltered picks sample rowids like get_random, which is cheaper:
+pub async fn get_random_quotes(
+    db: &SqlitePool,
+    filter: &TagFilter,
+    count: usize,
+    seed: Option<u64>,
+) -> Result<Vec<JsonQuote>, sqlx::Error> {
+    let mut rng = match seed {
+        Some(seed) => fastrand::Rng::with_seed(seed),
+        None => fastrand::Rng::new(),
+    };
+
+    let mut ids: Vec<String> = Vec::with_capacity(count);
+    let range = match filter.is_empty() && seed.is_none() {
+        true => rowid_range(db).await?,
+        false => None,
+    };
+    // Sampling most of the rowids would mostly draw repeats: the shuffle below is cheaper.
+    let sparse =
+        |(low, high): &(i64, i64)| (count as i64).saturating_mul(2) <= high.saturating_sub(*low);
+    if let Some((low, high)) = range.filter(sparse) {
+        // Each round looks up twice as many random rowids as there are quotes still wanted,
+        // in one query. Misses and repeats are thrown back, in the order they were drawn.
+        for _ in 0..RANDOM_ROWID_ROUNDS {
+            if ids.len() == count {
+                break;
+            }
+            let rowids: Vec<i64> = (0..(count - ids.len()) * 2)
+                .map(|_| rng.i64(low..=high))
+                .collect();
+            let mut query = QueryBuilder::new("select rowid, id from quotes where rowid in (");
+            let mut list = query.separated(", ");
+            for rowid in &rowids {
+                list.push_bind(rowid);
+            }
+            list.push_unseparated(")");
+            let found: HashMap<i64, String> = query
+                .build_query_as::<(i64, String)>()
+                .fetch_all(db)
+                .await?
+                .into_iter()
+                .collect();
+
+            for rowid in rowids {
+                if ids.len() == count {
+                    break;
+                }
+                if let Some(id) = found.get(&rowid) {
+                    if !ids.contains(id) {
+                        ids.push(id.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    if ids.len() < count {
+        // Every matching id is read, in id order, on every call. A seed has to pick from
+        // the same list whatever order the rows happen to be stored in, and a seeded pick
+        // from only part of that list would change whenever the part did. Only the ids are
+        // held, not the quotes.
+        let mut query = QueryBuilder::new("select id from quotes where 1 = 1");
+        filter.push_conditions(&mut query);
+        query.push(" order by id");
+        let mut rest: Vec<String> = query.build_query_scalar().fetch_all(db).await?;
+        rest.retain(|id| !ids.contains(id));
+
+        // A partial Fisher-Yates shuffle: the first few of rest become a uniform sample of it.
+        let wanted = (count - ids.len()).min(rest.len());
+        for i in 0..wanted {
+            let j = rng.usize(i..rest.len());
+            rest.swap(i, j);
+        }
+        ids.extend(rest.into_iter().take(wanted));
+    }
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut query = QueryBuilder::new("select ");
+    query.push(QUOTE_COLUMNS);
+    query.push(" from quotes where id in (");
+    let mut list = query.separated(", ");
+    for id in &ids {
+        list.push_bind(id);
+    }
+    list.push_unseparated(")");
+    let rows: Vec<QuoteRow> = query.build_query_as().fetch_all(db).await?;
+
+    // Put the quotes back in the order they were picked in.
+    let mut quotes: Vec<JsonQuote> = rows.into_iter().map(JsonQuote::from).collect();
+    quotes.sort_by_key(|json_quote| ids.iter().position(|id| *id == json_quote.id));
+    Ok(quotes)
+}
+
+// How an import treats quotes that are already in the database. The CLI and /import share
+// the default, so a file never overwrites quotes unless asked to:
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    #[default]
+    Insert, // Only add new quotes; existing ones are skipped.
+    Upsert,  // Add new quotes and overwrite existing ones that differ.
+    Replace, // Like upsert, then delete every quote that was not imported.
+}
+
+// What an import did, or would do in a dry run, with one quote:
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Inserted,  // The quote was new and was added.
+    Updated,   // The quote existed with different text, author or tags and was overwritten.
+    Unchanged, // The quote existed exactly as imported.
+    Skipped,   // The quote existed with differences, but the mode said to keep it.
+    Failed,    // The quote was invalid or could not be stored.
+}
+
+// What happened to one record of an import:
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportResult {
+    pub record: usize, // Position of the record in the input, counting from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // Id of the quote, once it is known.
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>, // How an updated or skipped quote differs from the stored one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>, // Why the record failed, if it did.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>, // Invalid fields of the record, if any.
+}
+
+// Totals and per-record results of an import:
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportReport {
+    pub dry_run: bool, // Nothing was written; the report says what would have happened.
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub deleted: usize, // Quotes removed because a replace import did not include them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deleted_ids: Vec<String>,
+    pub results: Vec<ImportResult>,
+}
+
+// Imports quotes one at a time, as they are read, and keeps the report. Used for both
+// the /import endpoint and the import subcommand. Each quote is stored in its own transaction, so a
+// failure only loses that quote.
+pub struct Importer<'a> {
+    db: &'a SqlitePool,
+    mode: ImportMode,
+    seen: HashSet<String>, // Ids imported so far, to catch repeats and for replace mode.
+    report: ImportReport,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(db: &'a SqlitePool, mode: ImportMode, dry_run: bool) -> Self {
+        Self {
+            db,
+            mode,
+            seen: HashSet::new(),
+            report: ImportReport {
+                dry_run,
+                ..ImportReport::default()
+            },
+        }
+    }
+
+    // Import one parsed record, recording the outcome in the report:
+    pub async fn record(&mut self, parsed: serde_json::Result<JsonQuote>) {
+        let record = self.report.results.len() + 1;
+        let mut result = ImportResult {
+            record,
+            id: None,
+            status: ImportStatus::Failed,
+            changes: Vec::new(),
+            detail: None,
+            errors: Vec::new(),
+        };
+
+        match parsed {
+            Err(e) => result.detail = Some(format!("not a quote: {}", e)),
+            Ok(mut json_quote) => match json_quote.validate() {
+                Err(errors) => {
+                    result.id = Some(json_quote.id).filter(|id| !id.is_empty());
+                    result.detail = Some("one or more fields are invalid".to_string());
+                    result.errors = errors;
+                }
+                Ok(()) => {
+                    json_quote.assign_id();
+                    result.id = Some(json_quote.id.clone());
+                    if !self.seen.insert(json_quote.id.clone()) {
+                        result.detail =
+                            Some("the same id appears earlier in this import".to_string());
+                    } else {
+                        match self.store(&json_quote).await {
+                            Ok((status, changes)) => {
+                                result.status = status;
+                                result.changes = changes;
+                            }
+                            Err(e) => {
+                                log::error!("import of quote {} failed: {}", json_quote.id, e);
+                                result.detail =
+                                    Some(format!("the quote could not be stored: {}", e));
+                            }
+                        }
+                    }
+                }
+            },
+        }
+
+        match result.status {
+            ImportStatus::Inserted => self.report.inserted += 1,
+            ImportStatus::Updated => self.report.updated += 1,
+            ImportStatus::Unchanged => self.report.unchanged += 1,
+            ImportStatus::Skipped => self.report.skipped += 1,
+            ImportStatus::Failed => self.report.failed += 1,
+        }
+        self.report.results.push(result);
+    }
+
+    // Import one NDJSON line. Blank lines are ignored:
+    pub async fn line(&mut self, line: &[u8]) {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        self.record(serde_json::from_slice(line)).await;
+    }
+
+    // Finish the import and hand back the report. A replace import deletes the quotes it did
+    // not see, but only if every record went in: a record that failed might have been meant
+    // to keep one of them.
+    pub async fn finish(mut self) -> Result<ImportReport, sqlx::Error> {
+        if self.mode == ImportMode::Replace && self.report.failed == 0 {
+            let mut tx = self.db.begin().await?;
+            let deleted = delete_all_except(&mut tx, &self.seen, self.report.dry_run).await?;
+            tx.commit().await?;
+            self.report.deleted = deleted.len();
+            self.report.deleted_ids = deleted;
+        }
+        Ok(self.report)
+    }
+
+    // Store one validated quote, or in a dry run only work out what storing it would do:
+    async fn store(
+        &self,
+        json_quote: &JsonQuote,
+    ) -> Result<(ImportStatus, Vec<String>), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        let (status, existing) = plan_import(&mut tx, json_quote, self.mode).await?;
+        if !self.report.dry_run {
+            match status {
+                ImportStatus::Inserted => insert(&mut tx, json_quote).await?,
+                ImportStatus::Updated => {
+                    update(&mut tx, json_quote).await?;
+                }
+                _ => (),
+            }
+        }
+        tx.commit().await?;
+
+        let changes = existing
+            .map(|existing| existing.changes(json_quote))
+            .unwrap_or_default();
+        Ok((status, changes))
+    }
+}
+
+// Given an open transaction, the quote with that id and its tags, if there is one:
+async fn get_in(
+    tx: &mut sqlx::SqliteConnection,
+    quote_id: &str,
+) -> Result<Option<JsonQuote>, sqlx::Error> {
+    let quote: Option<Quote> = sqlx::query_as("select * from quotes where id = $1;")
+        .bind(quote_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(quote) = quote else {
+        return Ok(None);
+    };
+
+    let tags: Vec<String> = sqlx::query_scalar("select tag from tags where quote_id = $1;")
+        .bind(quote_id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    Ok(Some(JsonQuote::new(quote, tags)))
+}
+
+// Given an open transaction and a validated quote with an id, work out what importing it
+// would do without writing anything. Also returns the stored quote with that id, if any:
+async fn plan_import(
+    tx: &mut sqlx::SqliteConnection,
+    json_quote: &JsonQuote,
+    mode: ImportMode,
+) -> Result<(ImportStatus, Option<JsonQuote>), sqlx::Error> {
+    let existing = get_in(tx, &json_quote.id).await?;
+    let status = match &existing {
+        None => ImportStatus::Inserted,
+        Some(existing) if existing.changes(json_quote).is_empty() => ImportStatus::Unchanged,
+        Some(_) if mode == ImportMode::Insert => ImportStatus::Skipped,
+        Some(_) => ImportStatus::Updated,
+    };
+    Ok((status, existing))
+}
+
+// Given an open transaction, delete every quote whose id is not in keep, with its tags.
+// Returns the deleted ids. With dry_run set, only returns the ids that would be deleted:
+async fn delete_all_except(
+    tx: &mut sqlx::SqliteConnection,
+    keep: &HashSet<String>,
+    dry_run: bool,
+) -> Result<Vec<String>, sqlx::Error> {
+    let ids: Vec<String> = sqlx::query_scalar("select id from quotes order by id;")
+        .fetch_all(&mut *tx)
+        .await?;
+    let doomed: Vec<String> = ids.into_iter().filter(|id| !keep.contains(id)).collect();
+
+    if !dry_run {
+        for id in &doomed {
+            sqlx::query("delete from tags where quote_id = $1;")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("delete from quotes where id = $1;")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    Ok(doomed)
+}
+
+// Given the database pool, stream every quote with its tags in id order:
+pub fn export(db: &SqlitePool) -> impl tokio_stream::Stream<Item = Result<JsonQuote, sqlx::Error>> {
+    stream(db.clone(), QuoteFilter::default(), QuoteSort::Id, None, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A migrated in-memory database holding generated quotes q0, q1, ..., each with
+    // tags_per_quote tags drawn from tag_names by a fixed seed, so every run sees the same data:
+    async fn scratch_db(quotes: usize, tag_names: &[String], tags_per_quote: usize) -> SqlitePool {
+        // One connection only, since every connection to :memory: gets a database of its own.
+        let db = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&db).await.unwrap();
+
+        let mut rng = fastrand::Rng::with_seed(42);
+        let mut tx = db.begin().await.unwrap();
+        for i in 0..quotes {
+            let quote = Quote {
+                id: format!("q{}", i),
+                quote: format!("Generated quote number {}", i),
+                author: format!("Author {}", i % 100),
+            };
+            let tags = match tag_names.is_empty() {
+                true => Vec::new(),
+                false => (0..tags_per_quote)
+                    .map(|_| tag_names[rng.usize(..tag_names.len())].clone())
+                    .collect(),
+            };
+            insert(&mut tx, &JsonQuote::new(quote, tags)).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+        db
+    }
+
+    // Upper 0.1% point of the standard normal distribution:
+    const Z_999: f64 = 3.090;
+
+    // Generate quotes, delete some of them again so the rowids have gaps, then pick random
+    // quotes and check with a chi-squared test that every remaining quote came up about
+    // equally often. The generators are seeded, so the outcome is the same on every run.
+    // With most quotes deleted, the picks mostly come from the random offset fallback.
+    #[tokio::test]
+    async fn get_random_is_uniform() {
+        for (quotes, delete_percent, picks) in [(100, 30, 20_000), (1000, 99, 5_000)] {
+            let db = scratch_db(quotes, &[], 0).await;
+            let mut rng = fastrand::Rng::with_seed(7);
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for i in 0..quotes {
+                let id = format!("q{}", i);
+                if rng.u8(..100) < delete_percent {
+                    sqlx::query("delete from quotes where id = $1;")
+                        .bind(&id)
+                        .execute(&db)
+                        .await
+                        .unwrap();
+                } else {
+                    counts.insert(id, 0);
+                }
+            }
+            assert!(counts.len() >= 2, "too few quotes left to pick from");
+
+            for _ in 0..picks {
+                let id = get_random(&db, &mut rng).await.unwrap();
+                *counts.get_mut(&id).expect("picked a deleted quote") += 1;
+            }
+
+            // Pearson's statistic, against its critical value at p = 0.001 by the
+            // Wilson-Hilferty approximation to the chi-squared distribution.
+            let expected = picks as f64 / counts.len() as f64;
+            let chi_squared: f64 = counts
+                .values()
+                .map(|&count| (count as f64 - expected).powi(2) / expected)
+                .sum();
+            let freedom = (counts.len() - 1) as f64;
+            let spread = 2.0 / (9.0 * freedom);
+            let critical = freedom * (1.0 - spread + Z_999 * spread.sqrt()).powi(3);
+            assert!(
+                chi_squared < critical,
+                "{} picks over {} quotes: chi-squared {:.1} exceeds {:.1}",
+                picks,
+                counts.len(),
+                chi_squared,
+                critical
+            );
+        }
+    }
+
+    // Time tagged lookups as the number of requested tags grows, in each match mode, on the
+    // tags index and then on the scratch database with the index dropped, and print the
+    // mean time of a lookup. Ignored by default since it checks nothing; run it with
+    //     cargo test --release bench_get_tagged -- --ignored --nocapture
+    #[tokio::test]
+    #[ignore]
+    async fn bench_get_tagged() {
+        use std::time::Instant;
+
+        const QUOTES: usize = 10_000;
+        const TAGS: usize = 5_000;
+        const TAGS_PER_QUOTE: usize = 3;
+        const RUNS: u32 = 20;
+
+        let tag_names: Vec<String> = (0..TAGS).map(|i| format!("tag {}", i)).collect();
+        let db = scratch_db(QUOTES, &tag_names, TAGS_PER_QUOTE).await;
+
+        println!(
+            "{} quotes, {} tags, {} tags per quote, mean of {} lookups in ms",
+            QUOTES, TAGS, TAGS_PER_QUOTE, RUNS
+        );
+        println!(
+            "{:<8} {:>6} {:>10} {:>10} {:>10}",
+            "index", "tags", "any", "all", "exclude"
+        );
+        for indexed in [true, false] {
+            if !indexed {
+                sqlx::query("drop index tags_tag_quote_id;")
+                    .execute(&db)
+                    .await
+                    .unwrap();
+            }
+            for count in [1, 10, 100, 1000, TAGS] {
+                let tags = tag_names[..count].join(",");
+                let filters = [
+                    TagFilter::new(Some(&tags), Some(TagMatch::Any), None),
+                    TagFilter::new(Some(&tags), Some(TagMatch::All), None),
+                    TagFilter::new(None, None, Some(&tags)),
+                ];
+                let mut means = Vec::with_capacity(filters.len());
+                for filter in &filters {
+                    let start = Instant::now();
+                    for _ in 0..RUNS {
+                        get_tagged(&db, filter).await.unwrap();
+                    }
+                    means.push(start.elapsed().as_secs_f64() * 1000.0 / f64::from(RUNS));
+                }
+                let index = if indexed { "with" } else { "without" };
+                println!(
+                    "{:<8} {:>6} {:>10.3} {:>10.3} {:>10.3}",
+                    index, count, means[0], means[1], means[2]
+                );
+            }
+        }
+    }
+
+    // Picks are distinct, and the same seed picks the same quotes even after every quote has
+    // been deleted and inserted again, which gives them new rowids:
+    #[tokio::test]
+    async fn get_random_quotes_are_distinct_and_seedable() {
+        let db = scratch_db(50, &[], 0).await;
+        let none = TagFilter::default();
+        let ids = |quotes: Vec<JsonQuote>| quotes.into_iter().map(|q| q.id).collect::<Vec<_>>();
+
+        for count in [1, 10, 50, 100] {
+            let picked = ids(get_random_quotes(&db, &none, count, None).await.unwrap());
+            let distinct: HashSet<&String> = picked.iter().collect();
+            assert_eq!(picked.len(), count.min(50));
+            assert_eq!(distinct.len(), picked.len());
+        }
+
+        let seeded = ids(get_random_quotes(&db, &none, 10, Some(5)).await.unwrap());
+        let quotes =
+            sqlx::query_as::<_, QuoteRow>(&format!("select {} from quotes;", QUOTE_COLUMNS))
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        sqlx::query("delete from tags; delete from quotes;")
+            .execute(&db)
+            .await
+            .unwrap();
+        let mut tx = db.begin().await.unwrap();
+        for row in quotes.into_iter().rev() {
+            insert(&mut tx, &JsonQuote::from(row)).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+        assert_eq!(
+            ids(get_random_quotes(&db, &none, 10, Some(5)).await.unwrap()),
+            seeded
+        );
+    }
+
+    // An empty table has nothing to pick:
+    #[tokio::test]
+    async fn get_random_on_empty_table() {
+        let db = scratch_db(0, &[], 0).await;
+        let picked = get_random(&db, &mut fastrand::Rng::with_seed(1)).await;
+        assert!(matches!(picked, Err(sqlx::Error::RowNotFound)));
+    }
+
+    // The author: scope covers the next word or phrase, with or without a space after it:
+    #[test]
+    fn search_expression_scopes_author() {
+        let scoped = Some("author : \"twain\"".to_string());
+        assert_eq!(search_expression("author:twain"), scoped);
+        assert_eq!(search_expression("author: twain"), scoped);
+        assert_eq!(search_expression("Author:  twain"), scoped);
+        assert_eq!(
+            search_expression("author: \"mark twain\" river"),
+            Some("author : \"mark twain\" \"river\"".to_string())
+        );
+        assert_eq!(search_expression("author: "), None);
+    }
+}
@@ -0,0 +1,157 @@
+/*
+This file manages the server-side state of tokens:
+    1) Refresh tokens, which are random strings handed to the client and stored only as
+       SHA-256 hashes. They rotate: each use revokes the token and issues a new one in the
+       same family. Reusing a revoked token revokes the whole family, since it means the
+       token was stolen.
+    2) The revocation list of access tokens (by jti) that were logged out before expiry.
+*/
+
+use crate::*;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+// A random identifier of 128 bits from the OS random source, as hex:
+pub fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+// A random refresh token of 256 bits from the OS random source, as hex:
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Refresh tokens are only ever stored and looked up by their hash:
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+// Create and store a new refresh token for a user, in the given family or in a new family
+// if none is given. Returns the token to hand to the client. Tokens that have expired are
+// cleared out at the same time:
+pub async fn issue_refresh_token(
+    db: &SqlitePool,
+    email: &str,
+    family: Option<&str>,
+    expires_at: i64,
+) -> Result<String, sqlx::Error> {
+    let token = random_token();
+    let family = family.map(str::to_string).unwrap_or_else(random_id);
+    let mut tx = db.begin().await?;
+
+    sqlx::query("delete from refresh_tokens where expires_at <= $1;")
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "insert into refresh_tokens (token_hash, family, email, expires_at) values ($1, $2, $3, $4);",
+    )
+    .bind(hash_token(&token))
+    .bind(family)
+    .bind(email)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(token)
+}
+
+// Use up a refresh token: it is revoked and its user email and family are returned so a
+// replacement can be issued. Unknown and expired tokens give None. A token that was already
+// revoked also gives None, and revokes every other token in its family.
+pub async fn redeem_refresh_token(
+    db: &SqlitePool,
+    token: &str,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    let token_hash = hash_token(token);
+
+    // Revoking and reading back happen in one statement, so when the same token is redeemed
+    // twice at once only one of the redeems gets a row back.
+    let redeemed: Option<(String, String)> = sqlx::query_as(
+        "update refresh_tokens set revoked = true
+            where token_hash = $1 and not revoked and expires_at > $2
+            returning email, family;",
+    )
+    .bind(&token_hash)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(db)
+    .await?;
+    if redeemed.is_some() {
+        return Ok(redeemed);
+    }
+
+    // Nothing was redeemed: the token is unknown, expired or already used. Only reuse
+    // means it was stolen.
+    let reused: Option<(String, String)> = sqlx::query_as(
+        "select email, family from refresh_tokens where token_hash = $1 and revoked;",
+    )
+    .bind(&token_hash)
+    .fetch_optional(db)
+    .await?;
+    if let Some((email, family)) = reused {
+        log::warn!("refresh token reuse for {}: revoking family", email);
+        sqlx::query("update refresh_tokens set revoked = true where family = $1;")
+            .bind(&family)
+            .execute(db)
+            .await?;
+    }
+
+    Ok(None)
+}
+
+// Revoke the whole family of a refresh token, e.g. on logout:
+pub async fn revoke_refresh_family(db: &SqlitePool, token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "update refresh_tokens set revoked = true
+            where family = (select family from refresh_tokens where token_hash = $1);",
+    )
+    .bind(hash_token(token))
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// Put an access token on the revocation list until it would have expired anyway. Entries
+// for tokens that have since expired are cleared out at the same time:
+pub async fn revoke_access_token(
+    db: &SqlitePool,
+    jti: &str,
+    expires_at: i64,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query("delete from revoked_tokens where expires_at <= $1;")
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("insert or ignore into revoked_tokens (jti, expires_at) values ($1, $2);")
+        .bind(jti)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+// Check whether an access token has been revoked:
+pub async fn is_revoked(db: &SqlitePool, jti: &str) -> Result<bool, sqlx::Error> {
+    let revoked =
+        sqlx::query_scalar::<_, i64>("select count(*) from revoked_tokens where jti = $1;")
+            .bind(jti)
+            .fetch_one(db)
+            .await?;
+    Ok(revoked > 0)
+}
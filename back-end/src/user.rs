@@ -0,0 +1,193 @@
+/*
+This file defines the User struct for registered accounts and the functions that create,
+look up and authenticate users in the database. Passwords are never stored: only their
+Argon2 hashes are, and hashing runs on the blocking thread pool so it does not stall the
+async runtime.
+*/
+
+use crate::*;
+
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use std::sync::LazyLock;
+
+// The hash of a password no account has. Logins for unregistered emails are checked against
+// it, so they take as long to turn down as wrong passwords and the timing of a failed login
+// does not tell whether the email is registered:
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(b"not the password of any account", &salt)
+        .expect("hashing a fixed password succeeds")
+        .to_string()
+});
+
+// What a user is allowed to do. Each role can do everything the roles before it can:
+//     reader: read the authenticated listing endpoints
+//     editor: also add and update quotes
+//     admin:  also delete quotes and manage users
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    ToSchema,
+    sqlx::Type,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Editor,
+    Admin,
+}
+
+// A registered user account:
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct User {
+    pub email: String,
+    pub full_name: String,
+    pub password_hash: String,
+    pub disabled: bool,
+    pub role: Role,
+}
+
+// The public view of a user account, as shown to admins:
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserInfo {
+    #[schema(example = "johnsmith@example.org")]
+    pub email: String,
+    #[schema(example = "John Smith")]
+    pub full_name: String,
+    pub role: Role,
+    pub disabled: bool,
+}
+
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            email: user.email,
+            full_name: user.full_name,
+            role: user.role,
+            disabled: user.disabled,
+        }
+    }
+}
+
+// Hash a password with Argon2 and a fresh random salt, giving the PHC string to store:
+pub async fn hash_password(password: String) -> Result<String, UserError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| UserError::PasswordHash(e.to_string()))
+    })
+    .await
+    .map_err(|e| UserError::PasswordHash(e.to_string()))?
+}
+
+// Check a password against a stored PHC hash string:
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool, UserError> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash)
+            .map_err(|e| UserError::PasswordHash(e.to_string()))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    })
+    .await
+    .map_err(|e| UserError::PasswordHash(e.to_string()))?
+}
+
+// Create a new user with a hashed password and the given role. Registration always asks for
+// a reader; admins are made with `quote-server user add --role admin`. Fails if the email is
+// already registered:
+pub async fn create(
+    db: &SqlitePool,
+    full_name: &str,
+    email: &str,
+    password: &str,
+    role: Role,
+) -> Result<User, UserError> {
+    let password_hash = hash_password(password.to_string()).await?;
+
+    let user = sqlx::query_as(
+        "insert into users (email, full_name, password_hash, role)
+            values ($1, $2, $3, $4)
+            returning email, full_name, password_hash, disabled, role;",
+    )
+    .bind(email)
+    .bind(full_name)
+    .bind(password_hash)
+    .bind(role)
+    .fetch_one(db)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(ref dbe) if dbe.is_unique_violation() => UserError::EmailTaken,
+        e => UserError::Database(e),
+    })?;
+
+    Ok(user)
+}
+
+// Given the database pool and an email, get the user registered with that email:
+pub async fn get_by_email(db: &SqlitePool, email: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as(
+        "select email, full_name, password_hash, disabled, role from users where email = $1;",
+    )
+    .bind(email)
+    .fetch_optional(db)
+    .await
+}
+
+// Check an email and password, giving back the user if they match an enabled account:
+pub async fn authenticate(db: &SqlitePool, email: &str, password: &str) -> Result<User, UserError> {
+    let user = get_by_email(db, email).await?;
+    let password_hash = match &user {
+        Some(user) => user.password_hash.clone(),
+        // The first use hashes, so it too runs off the async runtime.
+        None => tokio::task::spawn_blocking(|| DUMMY_HASH.clone())
+            .await
+            .map_err(|e| UserError::PasswordHash(e.to_string()))?,
+    };
+
+    let verified = verify_password(password.to_string(), password_hash).await?;
+    let user = match user {
+        Some(user) if verified => user,
+        _ => return Err(UserError::WrongCredentials),
+    };
+    if user.disabled {
+        return Err(UserError::Disabled);
+    }
+
+    Ok(user)
+}
+
+// Change the role and/or disabled flag of the account registered with an email, giving
+// back the updated account. Returns None if there is no such account:
+pub async fn update(
+    db: &SqlitePool,
+    email: &str,
+    role: Option<Role>,
+    disabled: Option<bool>,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as(
+        "update users set role = coalesce($1, role), disabled = coalesce($2, disabled)
+            where email = $3
+            returning email, full_name, password_hash, disabled, role;",
+    )
+    .bind(role)
+    .bind(disabled)
+    .bind(email)
+    .fetch_optional(db)
+    .await
+}
@@ -1,35 +1,94 @@
 /*
 This is an Axum GET handler that responds to queries to fetch a quote from the database. 
-It supports three types of requests via URL query parameters:
+It supports four types of requests via URL query parameters:
     1) ?id=... — Fetch a specific quote by ID.
-    2) ?tags=... — Fetch a quote matching one or more tags.
-    3) No query params — Return a random quote.
+    2) ?tags=...&mode=any|all&exclude=... — Fetch a quote matching any or all of the tags,
+       and none of the excluded ones.
+    3) ?q=... — Fetch the quote best matching a full-text search.
+    4) No query params — Return a random quote.
+Each visitor's last shown quote is kept in their own cookie, and is shown again
+if the database cannot pick a new one.
  */
 use crate::*;
 
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+// Name of the cookie holding the visitor's last shown quote:
+const LAST_QUOTE_COOKIE: &str = "last_quote";
+
+// The visitor's last shown quote and its tags, as stored in their cookie:
+#[derive(Serialize, Deserialize)]
+struct LastQuote {
+    quote: Quote,
+    tags: String,
+}
+
+impl LastQuote {
+    // Read the last shown quote from the visitor's cookie, if they have a valid one:
+    fn from_jar(jar: &CookieJar) -> Option<Self> {
+        let cookie = jar.get(LAST_QUOTE_COOKIE)?;
+        let json = URL_SAFE_NO_PAD.decode(cookie.value()).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    // Make the cookie that remembers this quote for the visitor:
+    fn to_cookie(&self) -> Option<Cookie<'static>> {
+        let json = serde_json::to_vec(self).ok()?;
+        let cookie = Cookie::build((LAST_QUOTE_COOKIE, URL_SAFE_NO_PAD.encode(json)))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .build();
+        Some(cookie)
+    }
+}
+
+// The default quote displayed before the visitor has been shown any other quote:
+fn default_quote() -> LastQuote {
+    LastQuote {
+        quote: Quote {
+            id: "101".to_string(),
+            quote: "Yesterday is history, tomorrow is a mystery, and today is a gift, that's why it's called the present.".to_string(),
+            author: "Turtle".to_string(),
+        },
+        tags: "empty".to_string(),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct GetquoteParams {
     id: Option<String>,
     tags: Option<String>,
+    mode: Option<TagMatch>,
+    exclude: Option<String>,
+    q: Option<String>,
 }
 
 pub async fn get_quote(
-    State(app_state): State<Arc<RwLock<AppState>>>,
+    State(app_state): State<SharedAppState>,
+    jar: CookieJar,
     Query(params): Query<GetquoteParams>,
 ) -> Result<response::Response, http::StatusCode> {
-    let mut app_writer = app_state.write().await;
-    let db = app_writer.db.clone();
+    let db = &app_state.db;
 
     // Specified.
     if let GetquoteParams { id: Some(id), .. } = params {
-        let quote_result = quote::get(&db, &id).await;
+        let quote_result = quote::get(db, &id).await;
         let result = match quote_result {
             Ok((quote, tags)) => {
                 let tag_string = tags.join(", ");
 
-                app_writer.current_quote = quote.clone();
-                let quote = IndexTemplate::new(quote.clone(), tag_string);
-                Ok(response::Html(quote.to_string()).into_response())
+                let last_quote = LastQuote {
+                    quote: quote.clone(),
+                    tags: tag_string.clone(),
+                };
+                let jar = match last_quote.to_cookie() {
+                    Some(cookie) => jar.add(cookie),
+                    None => jar,
+                };
+                let quote = IndexTemplate::new(quote, tag_string);
+                Ok((jar, response::Html(quote.to_string())).into_response())
             }
             Err(e) => {
                 log::warn!("quote fetch failed: {}", e);
@@ -39,18 +98,15 @@ pub async fn get_quote(
         return result;
     }
 
-    if let GetquoteParams { tags: Some(tags), .. } = params {
-        log::info!("quote tags: {}", tags);
-
-        let mut tags_string = String::new();
-        for c in tags.chars() {
-            if c.is_alphabetic() || c == ',' {
-                let cl: String = c.to_lowercase().collect();
-                tags_string.push_str(&cl);
-            }
-        }
+    let filter = TagFilter::new(
+        params.tags.as_deref(),
+        params.mode,
+        params.exclude.as_deref(),
+    );
+    if !filter.is_empty() {
+        log::info!("quote tags: {:?}", filter);
 
-        let quote_result = quote::get_tagged(&db, tags_string.split(',')).await;
+        let quote_result = quote::get_tagged(db, &filter).await;
         match quote_result {
             Ok(Some(id)) => {
                 let uri = format!("/?id={}", id);
@@ -66,7 +122,27 @@ pub async fn get_quote(
         }
     }
 
-    let quote_result = quote::get_random(&db).await;
+    if let GetquoteParams { q: Some(q), .. } = params {
+        log::info!("quote search: {}", q);
+
+        if let Some(expression) = quote::search_expression(&q) {
+            let search_result = quote::search(db, &expression, &TagFilter::default(), 1).await;
+            match search_result {
+                Ok(hits) if !hits.is_empty() => {
+                    let uri = format!("/?id={}", hits[0].quote.id);
+                    return Ok(response::Redirect::to(&uri).into_response());
+                }
+                Ok(_) => {
+                    log::info!("quote search was empty");
+                }
+                Err(e) => {
+                    log::error!("quote search database error: {}", e);
+                }
+            }
+        }
+    }
+
+    let quote_result = quote::get_random(db, &mut fastrand::Rng::new()).await;
     match quote_result {
         Ok(id) => {
             let uri = format!("/?id={}", id);
@@ -74,9 +150,8 @@ pub async fn get_quote(
         }
         Err(e) => {
             log::error!("random quote selection failed: {}", e);
-            let tag_string = "empty".to_string();
-            let quote = app_writer.current_quote.clone();
-            let quote = IndexTemplate::new(quote, tag_string);
+            let last_quote = LastQuote::from_jar(&jar).unwrap_or_else(default_quote);
+            let quote = IndexTemplate::new(last_quote.quote, last_quote.tags);
             Ok(response::Html(quote.to_string()).into_response())
         }
     }